@@ -4,6 +4,7 @@ set -euo pipefail
 http_proxy=${http_proxy:-}
 https_proxy=${https_proxy:-}
 ftp_proxy=${ftp_proxy:-}
+FSTYPE=${FSTYPE:-ext3}
 
 PACKAGES=$(sed "s/\n/ /g" packages)
 
@@ -15,6 +16,7 @@ docker build -t crashcart-builder \
 
 docker run --privileged --rm -i \
     -e "PACKAGES=${PACKAGES}" \
+    -e "FSTYPE=${FSTYPE}" \
     -e http_proxy="${http_proxy}" \
     -e https_proxy="${https_proxy}" \
     -e ftp_proxy="${ftp_proxy}" \
@@ -0,0 +1,107 @@
+use errors::*;
+use std::env;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A container to run on, described by label. Targets that could not be
+/// resolved carry the error instead of a pid, so that it ends up in the
+/// report next to the others.
+pub struct Target {
+    pub label: String,
+    pub pid: Result<u64>,
+}
+
+struct Outcome {
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    error: Option<String>,
+}
+
+/// Runs crashcart with args on each target, parallel at a time, and prints
+/// a report with the output and exit code of each. Every target gets its
+/// own process, since entering namespaces only works for a whole process.
+/// Returns 0 if the command succeeded everywhere and 1 otherwise.
+pub fn run(targets: Vec<Target>, args: &[String], cmd: &[String], parallel: usize) -> Result<i32> {
+    let exe = env::current_exe().chain_err(|| "failed to find crashcart")?;
+    let count = targets.len();
+    let queue = Arc::new(Mutex::new(targets.into_iter().enumerate().rev().collect::<Vec<_>>()));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let mut workers = Vec::new();
+    for _ in 0..parallel.clamp(1, count.max(1)) {
+        let queue = queue.clone();
+        let results = results.clone();
+        let exe = exe.clone();
+        let args = args.to_vec();
+        let cmd = cmd.to_vec();
+        workers.push(thread::spawn(move || loop {
+            let (index, target) = match queue.lock().unwrap().pop() {
+                Some(t) => t,
+                None => break,
+            };
+            let outcome = match target.pid {
+                Ok(pid) => {
+                    debug!("running on {}", target.label);
+                    let output = Command::new(&exe)
+                        .args(&args)
+                        .arg(format!("pid:{}", pid))
+                        .arg("--")
+                        .args(&cmd)
+                        .stdin(Stdio::null())
+                        .output();
+                    match output {
+                        Ok(o) => {
+                            Outcome {
+                                exit_code: o.status.code().or_else(|| {
+                                    o.status.signal().map(|s| s + 128)
+                                }),
+                                stdout: String::from_utf8_lossy(&o.stdout).into_owned(),
+                                stderr: String::from_utf8_lossy(&o.stderr).into_owned(),
+                                error: None,
+                            }
+                        }
+                        Err(e) => failed(format!("failed to run {:?}: {}", exe, e)),
+                    }
+                }
+                Err(e) => failed(e.to_string()),
+            };
+            results.lock().unwrap().push((index, target.label, outcome));
+        }));
+    }
+    for w in workers {
+        w.join().map_err(|_| "batch worker panicked")?;
+    }
+
+    let mut results = results.lock().unwrap();
+    results.sort_by_key(|r| r.0);
+    let mut succeeded = 0;
+    for (_, label, outcome) in results.iter() {
+        match (outcome.exit_code, &outcome.error) {
+            (_, Some(e)) => println!("==> {}: error: {}", label, e),
+            (Some(code), _) => println!("==> {}: exit {}", label, code),
+            (None, _) => println!("==> {}: no exit code", label),
+        }
+        if outcome.exit_code == Some(0) {
+            succeeded += 1;
+        }
+        print!("{}", outcome.stdout);
+        if !outcome.stderr.is_empty() {
+            println!("--- stderr");
+            print!("{}", outcome.stderr);
+        }
+        println!();
+    }
+    println!("{} of {} succeeded", succeeded, count);
+    Ok(if succeeded == count { 0 } else { 1 })
+}
+
+fn failed(error: String) -> Outcome {
+    Outcome {
+        exit_code: None,
+        stdout: String::new(),
+        stderr: String::new(),
+        error: Some(error),
+    }
+}
@@ -0,0 +1,484 @@
+use errors::*;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, umount2, MsFlags, MNT_DETACH};
+use nix::mount::{MS_BIND, MS_REC, MS_REMOUNT, MS_PRIVATE, MS_RDONLY, MS_NOSUID};
+use nix::mount::{MS_NODEV, MS_NOEXEC, MS_SYNCHRONOUS, MS_DIRSYNC, MS_NOATIME};
+use nix::mount::{MS_NODIRATIME, MS_RELATIME, MS_STRICTATIME, MS_MANDLOCK};
+use nix::sched::{setns, unshare, CloneFlags, CLONE_NEWUSER, CLONE_NEWNET};
+use nix::sched::{CLONE_NEWCGROUP, CLONE_NEWNS, CLONE_NEWPID, CLONE_NEWIPC, CLONE_NEWUTS};
+use nix::sys::stat::Mode;
+use nix::unistd::{chdir, chown, close, pivot_root, sethostname};
+use runtime;
+use serde_json::Value;
+use std::fs::{create_dir_all, metadata, read_dir, read_link, symlink_metadata, File};
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+const NAMESPACES: &[(&str, CloneFlags)] = &[
+    ("pid", CLONE_NEWPID),
+    ("network", CLONE_NEWNET),
+    ("mount", CLONE_NEWNS),
+    ("ipc", CLONE_NEWIPC),
+    ("uts", CLONE_NEWUTS),
+    ("user", CLONE_NEWUSER),
+    ("cgroup", CLONE_NEWCGROUP),
+];
+
+/// Mounts the crashcart tools at the given path.
+pub type Attach = dyn Fn(&Path) -> Result<()>;
+
+// (option, clears the flag, flag)
+const MOUNT_OPTIONS: &[(&str, bool, MsFlags)] = &[
+    ("ro", false, MS_RDONLY),
+    ("rw", true, MS_RDONLY),
+    ("nosuid", false, MS_NOSUID),
+    ("suid", true, MS_NOSUID),
+    ("nodev", false, MS_NODEV),
+    ("dev", true, MS_NODEV),
+    ("noexec", false, MS_NOEXEC),
+    ("exec", true, MS_NOEXEC),
+    ("sync", false, MS_SYNCHRONOUS),
+    ("async", true, MS_SYNCHRONOUS),
+    ("dirsync", false, MS_DIRSYNC),
+    ("mand", false, MS_MANDLOCK),
+    ("nomand", true, MS_MANDLOCK),
+    ("noatime", false, MS_NOATIME),
+    ("atime", true, MS_NOATIME),
+    ("nodiratime", false, MS_NODIRATIME),
+    ("diratime", true, MS_NODIRATIME),
+    ("relatime", false, MS_RELATIME),
+    ("norelatime", true, MS_RELATIME),
+    ("strictatime", false, MS_STRICTATIME),
+    ("nostrictatime", true, MS_STRICTATIME),
+    ("bind", false, MS_BIND),
+    ("rbind", false, MS_BIND),
+];
+
+// propagation is not kept, everything under the new root is private
+const PROPAGATION_OPTIONS: &[&str] = &[
+    "private",
+    "rprivate",
+    "shared",
+    "rshared",
+    "slave",
+    "rslave",
+    "unbindable",
+    "runbindable",
+];
+
+/// The OCI bundle of a container, used to rebuild the view the container
+/// had of the system after it has exited. The namespaces listed in the spec
+/// are joined if they still exist and created otherwise.
+pub struct Bundle {
+    dir: PathBuf,
+    spec: Value,
+    rootfs: PathBuf,
+    namespaces: Vec<(CloneFlags, Option<String>)>,
+}
+
+impl Bundle {
+    pub fn open(id: &str) -> Result<Bundle> {
+        let dir = runtime::find_bundle(id)?;
+        let spec = runtime::read_json(dir.join("config.json"))?;
+        let rootfs = match spec["root"]["path"].as_str() {
+            Some(p) => dir.join(p),
+            None => bail!("bundle {:?} has no root path", dir),
+        };
+        let empty = match read_dir(&rootfs) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(_) => true,
+        };
+        if empty {
+            bail!(
+                "root filesystem {:?} is missing or empty, it may have to be mounted first",
+                rootfs
+            );
+        }
+        info!("using bundle {:?} with root filesystem {:?}", dir, rootfs);
+        let namespaces = namespaces(&spec);
+        Ok(Bundle {
+            dir,
+            spec,
+            rootfs,
+            namespaces,
+        })
+    }
+
+    pub fn has_namespace(&self, space: CloneFlags) -> bool {
+        self.namespaces.iter().any(|&(s, _)| s == space)
+    }
+
+    /// Returns true if the namespace is in the spec and has to be created.
+    pub fn creates_namespace(&self, space: CloneFlags) -> bool {
+        self.namespaces.iter().any(
+            |&(s, ref path)| s == space && path.is_none(),
+        )
+    }
+
+    /// Joins or creates the namespaces in spaces that are in the spec. A
+    /// new mount namespace is always created.
+    pub fn enter_namespaces(&self, spaces: CloneFlags) -> Result<()> {
+        let mut new = if spaces.contains(CLONE_NEWNS) {
+            CLONE_NEWNS
+        } else {
+            CloneFlags::empty()
+        };
+        for &(space, ref path) in &self.namespaces {
+            // the mount namespace of the container is never reused
+            if !spaces.contains(space) || space == CLONE_NEWNS {
+                continue;
+            }
+            match path {
+                Some(p) => {
+                    debug!("joining namespace {}", p);
+                    let fd = open(p.as_str(), OFlag::empty(), Mode::empty())
+                        .chain_err(|| format!("failed to open {}", p))?;
+                    defer!(close(fd).unwrap());
+                    setns(fd, space).chain_err(|| format!("failed to enter {}", p))?;
+                }
+                None => new |= space,
+            }
+        }
+        if !new.is_empty() {
+            unshare(new).chain_err(|| "failed to unshare")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the uid_map and gid_map for a new user namespace.
+    pub fn id_maps(&self) -> (String, String) {
+        let map = |key: &str| {
+            let empty = Vec::new();
+            self.spec["linux"][key]
+                .as_array()
+                .unwrap_or(&empty)
+                .iter()
+                .map(|m| {
+                    format!(
+                        "{} {} {}\n",
+                        m["containerID"].as_u64().unwrap_or(0),
+                        m["hostID"].as_u64().unwrap_or(0),
+                        m["size"].as_u64().unwrap_or(0)
+                    )
+                })
+                .collect::<String>()
+        };
+        (map("uidMappings"), map("gidMappings"))
+    }
+
+    /// Returns the host id that root in the container maps to.
+    fn host_root(&self, key: &str) -> u32 {
+        let empty = Vec::new();
+        self.spec["linux"][key]
+            .as_array()
+            .unwrap_or(&empty)
+            .iter()
+            .find(|m| m["containerID"].as_u64() == Some(0))
+            .and_then(|m| m["hostID"].as_u64())
+            .unwrap_or(0) as u32
+    }
+
+    /// Builds the root filesystem of the container from the spec in the
+    /// current mount namespace, calls attach to mount the tools at
+    /// mount_path inside it and makes it the root.
+    pub fn setup_root(&self, mount_path: &str, attach: &Attach) -> Result<()> {
+        // keep all of this from propagating back to the host
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MS_REC | MS_PRIVATE,
+            None::<&str>,
+        ).chain_err(|| "failed to make / private")?;
+        mount(
+            Some(&self.rootfs),
+            &self.rootfs,
+            None::<&str>,
+            MS_BIND | MS_REC,
+            None::<&str>,
+        ).chain_err(|| format!("failed to bind {:?}", self.rootfs))?;
+
+        let empty = Vec::new();
+        for m in self.spec["mounts"].as_array().unwrap_or(&empty) {
+            let dest = m["destination"].as_str().unwrap_or_default();
+            if let Err(e) = self.mount_one(m) {
+                warn!("skipping mount on {}: {}", dest, e);
+            }
+        }
+
+        let target = secure_join(&self.rootfs, mount_path);
+        create_dir_all(&target).chain_err(
+            || format!("failed to create {:?}", target),
+        )?;
+        attach(&target)?;
+
+        if self.spec["root"]["readonly"].as_bool() == Some(true) {
+            mount(
+                None::<&str>,
+                &self.rootfs,
+                None::<&str>,
+                MS_BIND | MS_REMOUNT | MS_RDONLY,
+                None::<&str>,
+            ).chain_err(|| "failed to make root read only")?;
+        }
+
+        if let Some(hostname) = self.spec["hostname"].as_str() {
+            if self.creates_namespace(CLONE_NEWUTS) {
+                sethostname(hostname).chain_err(|| "failed to set hostname")?;
+            }
+        }
+
+        // stack the old root under the new one and detach it
+        chdir(&self.rootfs).chain_err(
+            || format!("failed to chdir to {:?}", self.rootfs),
+        )?;
+        pivot_root(".", ".").chain_err(|| "failed to pivot root")?;
+        umount2(".", MNT_DETACH).chain_err(|| "failed to detach old root")?;
+        chdir("/").chain_err(|| "failed to chdir to /")?;
+        Ok(())
+    }
+
+    fn mount_one(&self, m: &Value) -> Result<()> {
+        let dest = m["destination"].as_str().unwrap_or_default();
+        let fstype = m["type"].as_str().unwrap_or_default();
+        let source = m["source"].as_str().unwrap_or_default();
+        let mut flags = MsFlags::empty();
+        let mut data = Vec::new();
+        let mut recursive = false;
+        let empty = Vec::new();
+        for o in m["options"].as_array().unwrap_or(&empty) {
+            let o = o.as_str().unwrap_or_default();
+            if o == "rbind" {
+                recursive = true;
+            }
+            match MOUNT_OPTIONS.iter().find(|f| f.0 == o) {
+                Some(&(_, true, flag)) => flags.remove(flag),
+                Some(&(_, false, flag)) => flags.insert(flag),
+                None if PROPAGATION_OPTIONS.contains(&o) => {}
+                None => data.push(o),
+            }
+        }
+        let bind = flags.contains(MS_BIND) || fstype == "bind";
+        let target = secure_join(&self.rootfs, dest);
+
+        if bind {
+            let source = self.dir.join(source);
+            let is_file = !metadata(&source)
+                .chain_err(|| format!("failed to stat {:?}", source))?
+                .is_dir();
+            make_target(&target, is_file)?;
+            let rec = if recursive { MS_REC } else { MsFlags::empty() };
+            mount(
+                Some(&source),
+                &target,
+                None::<&str>,
+                MS_BIND | rec,
+                None::<&str>,
+            ).chain_err(|| format!("failed to bind {:?}", source))?;
+            // bind mounts ignore the other flags until remounted
+            flags.remove(MS_BIND);
+            if !flags.is_empty() {
+                mount(
+                    None::<&str>,
+                    &target,
+                    None::<&str>,
+                    MS_BIND | MS_REMOUNT | flags,
+                    None::<&str>,
+                ).chain_err(|| format!("failed to remount {:?}", target))?;
+            }
+        } else {
+            make_target(&target, false)?;
+            let data = data.join(",");
+            mount(
+                Some(source),
+                &target,
+                Some(fstype),
+                flags,
+                Some(&*data),
+            ).chain_err(|| format!("failed to mount {} to {:?}", fstype, target))?;
+            // a runtime would have made it from inside the user namespace,
+            // so it would belong to root in the container
+            if fstype == "tmpfs" && self.has_namespace(CLONE_NEWUSER) {
+                let uid = self.host_root("uidMappings");
+                let gid = self.host_root("gidMappings");
+                chown(&target, Some(uid), Some(gid)).chain_err(
+                    || format!("failed to chown {:?}", target),
+                )?;
+            }
+        }
+        debug!("mounted {} on {}", source, dest);
+        Ok(())
+    }
+}
+
+/// Returns the namespaces of the spec along with the path of the namespace
+/// to join, if it is given and still exists.
+fn namespaces(spec: &Value) -> Vec<(CloneFlags, Option<String>)> {
+    let mut spaces = Vec::new();
+    let empty = Vec::new();
+    let listed = spec["linux"]["namespaces"].as_array().unwrap_or(&empty);
+    for ns in listed {
+        let flag = match NAMESPACES.iter().find(|n| Some(n.0) == ns["type"].as_str()) {
+            Some(&(_, flag)) => flag,
+            None => continue,
+        };
+        let path = ns["path"].as_str().filter(|p| !p.is_empty());
+        let path = match path {
+            Some(p) if metadata(p).is_ok() => Some(p.to_string()),
+            Some(p) => {
+                warn!("namespace {} is gone, creating a new one", p);
+                None
+            }
+            None => None,
+        };
+        spaces.push((flag, path));
+    }
+    spaces
+}
+
+fn make_target(target: &Path, is_file: bool) -> Result<()> {
+    if !is_file {
+        return create_dir_all(target).chain_err(|| format!("failed to create {:?}", target));
+    }
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent).chain_err(
+            || format!("failed to create {:?}", parent),
+        )?;
+    }
+    if symlink_metadata(target).is_err() {
+        File::create(target).chain_err(
+            || format!("failed to create {:?}", target),
+        )?;
+    }
+    Ok(())
+}
+
+/// Joins path to root, resolving symlinks as if root was /, so that a
+/// container can not point a mount outside of its root filesystem.
+fn secure_join(root: &Path, path: &str) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    let mut pending: Vec<PathBuf> = vec![PathBuf::from(path)];
+    let mut links = 0;
+    while let Some(p) = pending.pop() {
+        let mut components = p.components();
+        let c = match components.next() {
+            Some(c) => c,
+            None => continue,
+        };
+        let rest = components.as_path().to_path_buf();
+        if !rest.as_os_str().is_empty() {
+            pending.push(rest);
+        }
+        match c {
+            Component::Normal(name) => {
+                let next = resolved.join(name);
+                let full = root.join(&next);
+                let is_link = symlink_metadata(&full)
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false);
+                if !is_link || links > 255 {
+                    resolved = next;
+                    continue;
+                }
+                links += 1;
+                let target = match read_link(&full) {
+                    Ok(t) => t,
+                    Err(_) => {
+                        resolved = next;
+                        continue;
+                    }
+                };
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                pending.push(target);
+            }
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    root.join(resolved)
+}
+
+/// Writes the id maps of a process that created a new user namespace.
+pub fn write_id_maps(pid: i32, uid_map: &str, gid_map: &str) -> Result<()> {
+    for &(file, map) in &[("uid_map", uid_map), ("gid_map", gid_map)] {
+        let path = format!("/proc/{}/{}", pid, file);
+        File::create(&path)
+            .and_then(|mut f| f.write_all(map.as_bytes()))
+            .chain_err(|| format!("failed to write {}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::secure_join;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Makes an empty directory to use as the root of a container.
+    fn root(name: &str) -> PathBuf {
+        let root = temp_dir().join(format!("crashcart-{}-{}", name, process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("usr/lib")).unwrap();
+        root
+    }
+
+    #[test]
+    fn plain_paths() {
+        let root = root("plain");
+        assert_eq!(secure_join(&root, "/usr/lib"), root.join("usr/lib"));
+        assert_eq!(secure_join(&root, "usr/./lib"), root.join("usr/lib"));
+        assert_eq!(secure_join(&root, "/usr/missing"), root.join("usr/missing"));
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn absolute_symlinks() {
+        let root = root("absolute");
+        symlink("/usr/lib", root.join("lib")).unwrap();
+        symlink("/etc", root.join("usr/etc")).unwrap();
+        assert_eq!(secure_join(&root, "/lib/x"), root.join("usr/lib/x"));
+        assert_eq!(secure_join(&root, "/usr/etc"), root.join("etc"));
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn relative_symlinks() {
+        let root = root("relative");
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink("../lib", root.join("usr/lib/up")).unwrap();
+        assert_eq!(secure_join(&root, "/lib/x"), root.join("usr/lib/x"));
+        assert_eq!(secure_join(&root, "/usr/lib/up"), root.join("usr/lib"));
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parent_escapes() {
+        let root = root("escape");
+        symlink("../../../../etc", root.join("usr/escape")).unwrap();
+        symlink("/../..", root.join("top")).unwrap();
+        assert_eq!(secure_join(&root, "/../../etc"), root.join("etc"));
+        assert_eq!(secure_join(&root, "usr/../../etc"), root.join("etc"));
+        assert_eq!(secure_join(&root, "/usr/escape"), root.join("etc"));
+        assert_eq!(secure_join(&root, "/top/etc"), root.join("etc"));
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn symlink_loops() {
+        let root = root("loop");
+        symlink("b", root.join("a")).unwrap();
+        symlink("/a", root.join("b")).unwrap();
+        symlink("self/x", root.join("self")).unwrap();
+        assert!(secure_join(&root, "/a/x").starts_with(&root));
+        assert!(secure_join(&root, "/self").starts_with(&root));
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(deprecated)]
+
 error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
@@ -6,15 +8,30 @@ error_chain! {
         Nix(::nix::Error);
         Io(::std::io::Error);
         Caps(::caps::Error);
+        Json(::serde_json::Error);
     }
-    // errors {
-    //     InvalidSpec(t: String) {
-    //         description("invalid spec")
-    //         display("invalid spec: '{}'", t)
-    //     }
-    //     SeccompError(t: String) {
-    //         description("seccomp spec")
-    //         display("seccomp error: '{}'", t)
-    //     }
-    // }
+    errors {
+        TargetChanged(pid: u64) {
+            description("target process changed")
+            display("process {} exited or its pid was reused", pid)
+        }
+        AmbiguousId(id: String, candidates: Vec<::runtime::Candidate>) {
+            description("ambiguous id")
+            display(
+                "id {} is ambiguous, candidates are:\n    {}",
+                id,
+                candidates
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n    ")
+            )
+        }
+    }
+}
+
+/// Returns the error and its causes on one line, for errors that are only
+/// logged.
+pub fn chain(e: &Error) -> String {
+    e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
 }
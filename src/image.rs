@@ -0,0 +1,70 @@
+use errors::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+const EXT_MAGIC: u16 = 0xEF53;
+const EXT_SUPERBLOCK: usize = 1024;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+// incompat and ro_compat features an ext3 driver understands
+const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x8 | 0x10;
+const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+const SQUASHFS_MAGIC: u32 = 0x73717368;
+const SQUASHFS_COMPRESSION: &[&str] = &["", "gzip", "lzma", "lzo", "xz", "lz4", "zstd"];
+const EROFS_MAGIC: u32 = 0xE0F5E1E2;
+const EROFS_SUPERBLOCK: usize = 1024;
+
+fn u16_at(buf: &[u8], off: usize) -> u16 {
+    u16::from(buf[off]) | (u16::from(buf[off + 1]) << 8)
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from(u16_at(buf, off)) | (u32::from(u16_at(buf, off + 2)) << 16)
+}
+
+/// Returns the filesystem type to mount image with, read from the magic
+/// number in its superblock. The filesystem starts at offset in the image.
+pub fn fs_type(image: &str, offset: u64) -> Result<&'static str> {
+    let mut buf = vec![0u8; 2048];
+    let mut f = File::open(image).chain_err(
+        || format!("failed to open {}", image),
+    )?;
+    f.seek(SeekFrom::Start(offset)).chain_err(
+        || format!("failed to seek in {}", image),
+    )?;
+    let mut len = 0;
+    while len < buf.len() {
+        match f.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) => return Err(e).chain_err(|| format!("failed to read {}", image)),
+        }
+    }
+    buf.truncate(len);
+
+    if buf.len() >= 4 && u32_at(&buf, 0) == SQUASHFS_MAGIC {
+        if buf.len() >= 22 {
+            let comp = u16_at(&buf, 20) as usize;
+            let name = SQUASHFS_COMPRESSION.get(comp).unwrap_or(&"unknown");
+            debug!("{} is squashfs compressed with {}", image, name);
+        }
+        return Ok("squashfs");
+    }
+    if buf.len() >= EROFS_SUPERBLOCK + 4 && u32_at(&buf, EROFS_SUPERBLOCK) == EROFS_MAGIC {
+        return Ok("erofs");
+    }
+    let sb = EXT_SUPERBLOCK;
+    if buf.len() >= sb + 104 && u16_at(&buf, sb + 56) == EXT_MAGIC {
+        let compat = u32_at(&buf, sb + 92);
+        let incompat = u32_at(&buf, sb + 96);
+        let ro_compat = u32_at(&buf, sb + 100);
+        if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+            return Ok("ext4");
+        }
+        if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+            return Ok("ext3");
+        }
+        return Ok("ext2");
+    }
+    bail!("{} is not an ext2/3/4, squashfs or erofs image", image)
+}
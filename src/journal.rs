@@ -0,0 +1,152 @@
+use errors::*;
+use nix::fcntl::{open, O_APPEND, O_CLOEXEC, O_CREAT, O_WRONLY};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, fsync, write};
+use process::Process;
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::unix::io::RawFd;
+
+const JOURNAL_DIR: &str = "/run/crashcart/journal";
+
+/// A change made in the mount namespace of a container.
+#[derive(Debug)]
+pub enum Step {
+    /// /dev was remounted read/write and the /dev/readonly sentinel created
+    Remount,
+    Mkdir(String),
+    Tmpfs(String),
+    Mknod(String),
+    Mount(String),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Remount => write!(f, "remount"),
+            Step::Mkdir(ref p) => write!(f, "mkdir {}", p),
+            Step::Tmpfs(ref p) => write!(f, "tmpfs {}", p),
+            Step::Mknod(ref p) => write!(f, "mknod {}", p),
+            Step::Mount(ref p) => write!(f, "mount {}", p),
+        }
+    }
+}
+
+impl Step {
+    fn parse(line: &str) -> Option<Step> {
+        let mut parts = line.splitn(2, ' ');
+        let step = parts.next()?;
+        let path = parts.next().map(str::to_string);
+        match step {
+            "remount" => Some(Step::Remount),
+            "mkdir" => path.map(Step::Mkdir),
+            "tmpfs" => path.map(Step::Tmpfs),
+            "mknod" => path.map(Step::Mknod),
+            "mount" => path.map(Step::Mount),
+            _ => None,
+        }
+    }
+}
+
+/// The journal of the changes made in the mount namespace of a container,
+/// so that they can be undone by `crashcart recover` if crashcart is
+/// killed before it cleans up. Every step is on disk before it is done.
+/// The journal is opened on the host and can be written to from inside
+/// the namespace.
+pub struct Journal {
+    fd: RawFd,
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+impl Journal {
+    /// Opens the journal of the mount namespace of target, noting the pid
+    /// so that the namespace can be entered again later.
+    pub fn open(target: &Process) -> Result<Journal> {
+        create_dir_all(JOURNAL_DIR).chain_err(|| {
+            format!("failed to create {}", JOURNAL_DIR)
+        })?;
+        let p = path(target.mount_ns()?);
+        let fd = open(
+            &*p,
+            O_WRONLY | O_APPEND | O_CREAT | O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        ).chain_err(|| format!("failed to open {}", p))?;
+        let journal = Journal { fd };
+        journal.write(&format!("pid {}", target.pid()))?;
+        Ok(journal)
+    }
+
+    /// Records step, which is about to be done.
+    pub fn record(&self, step: &Step) -> Result<()> {
+        self.write(&step.to_string())
+    }
+
+    fn write(&self, line: &str) -> Result<()> {
+        write(self.fd, format!("{}\n", line).as_bytes()).chain_err(
+            || "failed to write journal",
+        )?;
+        fsync(self.fd).chain_err(|| "failed to sync journal")?;
+        Ok(())
+    }
+}
+
+fn path(ns: u64) -> String {
+    format!("{}/{}", JOURNAL_DIR, ns)
+}
+
+/// Returns the mount namespaces that have a journal.
+pub fn namespaces() -> Result<Vec<u64>> {
+    let entries = match read_dir(JOURNAL_DIR) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).chain_err(|| format!("failed to read {}", JOURNAL_DIR)),
+    };
+    let mut namespaces = Vec::new();
+    for entry in entries {
+        let entry = entry.chain_err(|| format!("failed to read {}", JOURNAL_DIR))?;
+        if let Ok(ns) = entry.file_name().to_string_lossy().parse::<u64>() {
+            namespaces.push(ns);
+        }
+    }
+    Ok(namespaces)
+}
+
+/// Returns the pids that opened the journal of ns and the steps in it, in
+/// the order they were done.
+pub fn read(ns: u64) -> Result<(Vec<u64>, Vec<Step>)> {
+    let p = path(ns);
+    let f = File::open(&p).chain_err(|| format!("failed to open {}", p))?;
+    let mut pids = Vec::new();
+    let mut steps = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.chain_err(|| format!("failed to read {}", p))?;
+        if let Some(pid) = line.strip_prefix("pid ") {
+            if let Ok(pid) = pid.parse::<u64>() {
+                pids.push(pid);
+            }
+        } else if let Some(step) = Step::parse(&line) {
+            steps.push(step);
+        } else {
+            // the last line may be cut short by a crash
+            warn!("ignoring {:?} in {}", line, p);
+        }
+    }
+    Ok((pids, steps))
+}
+
+/// Removes the journal of ns, once everything in it has been undone.
+pub fn remove(ns: u64) -> Result<()> {
+    let p = path(ns);
+    if let Err(e) = remove_file(&p) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e).chain_err(|| format!("could not delete {}", p));
+        }
+    }
+    Ok(())
+}
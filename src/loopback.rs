@@ -1,17 +1,73 @@
 use libc;
 use nix::{Errno, Result};
+use std::mem;
 use std::os::unix::io::RawFd;
 
 const LOOP_MAJOR: u64 = 7;
 
 #[cfg(target_env = "musl")]
-const LOOP_SET_FD: libc::c_int = 0x4C00;
-#[cfg(target_env = "musl")]
-const LOOP_CTL_GET_FREE: libc::c_int = 0x4C82;
-#[cfg(not(target_env = "musl"))]
-const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+type Request = libc::c_int;
 #[cfg(not(target_env = "musl"))]
-const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+type Request = libc::c_ulong;
+
+const LOOP_SET_FD: Request = 0x4C00;
+const LOOP_CLR_FD: Request = 0x4C01;
+const LOOP_SET_STATUS64: Request = 0x4C04;
+const LOOP_GET_STATUS64: Request = 0x4C05;
+const LOOP_SET_DIRECT_IO: Request = 0x4C08;
+const LOOP_SET_BLOCK_SIZE: Request = 0x4C09;
+const LOOP_CONFIGURE: Request = 0x4C0A;
+const LOOP_CTL_GET_FREE: Request = 0x4C82;
+
+pub const LO_FLAGS_READ_ONLY: u32 = 1;
+pub const LO_FLAGS_AUTOCLEAR: u32 = 4;
+pub const LO_FLAGS_DIRECT_IO: u32 = 16;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+/// How a loop device is set up. A block_size of 0 keeps the default. The
+/// name is kept in lo_crypt_name (which is not used for anything else
+/// since cryptoloop is gone), so that the device can be recognized later.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub flags: u32,
+    pub block_size: u32,
+    pub offset: u64,
+    pub name: String,
+}
+
+/// What a loop device is attached to: the device and inode of the backing
+/// file, and where in it the device starts.
+pub struct Status {
+    pub device: u64,
+    pub inode: u64,
+    pub offset: u64,
+    pub flags: u32,
+    pub name: String,
+}
 
 pub fn loop_ctl_get_free(fd: RawFd) -> Result<i32> {
     let devnr = unsafe { libc::ioctl(fd, LOOP_CTL_GET_FREE) };
@@ -23,6 +79,91 @@ pub fn loop_set_fd(fd: RawFd, source: RawFd) -> Result<()> {
     Errno::result(res).map(drop)
 }
 
+pub fn loop_clr_fd(fd: RawFd) -> Result<()> {
+    let res = unsafe { libc::ioctl(fd, LOOP_CLR_FD) };
+    Errno::result(res).map(drop)
+}
+
+fn info(config: &Config) -> LoopInfo64 {
+    let mut info: LoopInfo64 = unsafe { mem::zeroed() };
+    info.lo_offset = config.offset;
+    info.lo_flags = config.flags;
+    // leave room for the terminating nul
+    let name = config.name.as_bytes();
+    let len = name.len().min(info.lo_crypt_name.len() - 1);
+    info.lo_crypt_name[..len].copy_from_slice(&name[..len]);
+    info
+}
+
+pub fn loop_set_status64(fd: RawFd, config: &Config) -> Result<()> {
+    let info = info(config);
+    let res = unsafe { libc::ioctl(fd, LOOP_SET_STATUS64, &info as *const LoopInfo64) };
+    Errno::result(res).map(drop)
+}
+
+pub fn loop_get_status64(fd: RawFd) -> Result<Status> {
+    let mut info: LoopInfo64 = unsafe { mem::zeroed() };
+    let res = unsafe { libc::ioctl(fd, LOOP_GET_STATUS64, &mut info as *mut LoopInfo64) };
+    Errno::result(res)?;
+    Ok(Status {
+        device: info.lo_device,
+        inode: info.lo_inode,
+        offset: info.lo_offset,
+        flags: info.lo_flags,
+        name: String::from_utf8_lossy(info.lo_crypt_name.split(|b| *b == 0).next().unwrap_or_default())
+            .into_owned(),
+    })
+}
+
+pub fn loop_set_block_size(fd: RawFd, size: u32) -> Result<()> {
+    let res = unsafe { libc::ioctl(fd, LOOP_SET_BLOCK_SIZE, size as libc::c_ulong) };
+    Errno::result(res).map(drop)
+}
+
+pub fn loop_set_direct_io(fd: RawFd, on: bool) -> Result<()> {
+    let res = unsafe { libc::ioctl(fd, LOOP_SET_DIRECT_IO, on as libc::c_ulong) };
+    Errno::result(res).map(drop)
+}
+
+/// Attaches source to the loop device fd as given by config in one step.
+/// Kernels before 5.8 do not have LOOP_CONFIGURE, so there the device is
+/// attached first and then set up, and detached again if that fails.
+pub fn loop_configure(fd: RawFd, source: RawFd, config: &Config) -> Result<()> {
+    let lc = LoopConfig {
+        fd: source as u32,
+        block_size: config.block_size,
+        info: info(config),
+        reserved: [0; 8],
+    };
+    let res = unsafe { libc::ioctl(fd, LOOP_CONFIGURE, &lc as *const LoopConfig) };
+    match Errno::result(res) {
+        Err(e) if e.errno() == Errno::EINVAL || e.errno() == Errno::ENOTTY => {
+            debug!("LOOP_CONFIGURE is not supported, falling back to LOOP_SET_FD");
+        }
+        res => return res.map(drop),
+    }
+
+    loop_set_fd(fd, source)?;
+    let setup = || -> Result<()> {
+        // the direct io flag can only be changed on its own
+        let mut status = config.clone();
+        status.flags &= !(LO_FLAGS_DIRECT_IO | LO_FLAGS_READ_ONLY);
+        loop_set_status64(fd, &status)?;
+        if config.block_size != 0 {
+            loop_set_block_size(fd, config.block_size)?;
+        }
+        if config.flags & LO_FLAGS_DIRECT_IO != 0 {
+            loop_set_direct_io(fd, true)?;
+        }
+        Ok(())
+    };
+    if let Err(e) = setup() {
+        let _ = loop_clr_fd(fd);
+        return Err(e);
+    }
+    Ok(())
+}
+
 pub fn makedev(major: u64, minor: u64) -> u64 {
     (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12) | ((major & !0xfff) << 32)
 }
@@ -9,75 +9,178 @@ extern crate log;
 extern crate nix;
 #[macro_use]
 extern crate scopeguard;
+#[macro_use]
+extern crate serde_json;
 
+mod batch;
+mod bundle;
 mod errors;
+mod image;
+mod journal;
 mod logger;
 mod loopback;
+mod mount_api;
+mod process;
+mod runtime;
+mod session;
+mod status;
+mod wait;
 
 use errors::*;
 use getopts::Options;
-use glob::glob;
+use bundle::{Attach, Bundle};
+use journal::{Journal, Step};
 use nix::c_int;
-use nix::fcntl::{open, OFlag, O_RDWR, O_CREAT, flock, FlockArg};
-use nix::mount::{mount, umount, MS_RDONLY, MsFlags};
-use nix::mount::{MS_REMOUNT, MS_NOSUID, MS_STRICTATIME};
+use nix::fcntl::{open, OFlag, O_CLOEXEC, O_RDONLY, O_RDWR, O_CREAT, O_DIRECTORY, flock, FlockArg};
+use nix::mount::{mount, umount, umount2, MS_RDONLY, MsFlags, MNT_DETACH};
+use nix::mount::{MS_REMOUNT, MS_NOSUID, MS_STRICTATIME, MS_PRIVATE, MS_BIND, MS_REC};
 use nix::sched::{CloneFlags, CLONE_NEWUSER, CLONE_NEWNET, CLONE_NEWCGROUP};
-use nix::sched::{setns, CLONE_NEWNS, CLONE_NEWPID, CLONE_NEWIPC, CLONE_NEWUTS};
+use nix::sched::{setns, unshare, CLONE_NEWNS, CLONE_NEWPID, CLONE_NEWIPC, CLONE_NEWUTS};
 use nix::sys::signal::{sigaction, kill};
 use nix::sys::signal::{SigAction, SigHandler, SaFlags, SigSet, Signal};
 use nix::sys::stat::{mknod, S_IFBLK, Mode, fstat};
 use nix::sys::wait::{waitpid, WaitStatus};
 use nix::unistd::{close, fork, ForkResult, execvp, setresgid, setresuid};
+use nix::unistd::{chdir, chroot, fchdir, pipe, pipe2, read, write};
 use nix::Errno;
+use process::Process;
+use runtime::{Candidate, RuntimeResolver};
+use std::collections::HashSet;
 use std::env;
-use std::fs::{read_link, create_dir_all, remove_file, remove_dir};
-use std::fs::{File, canonicalize, metadata};
-use std::io::{BufRead, BufReader};
+use std::fs::{read_link, read_dir, create_dir, create_dir_all, remove_file, remove_dir};
+use std::fs::{File, metadata};
+use std::io::{stdin, stdout, BufRead, BufReader};
 use std::io::prelude::*;
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::ffi::CString;
 
 
-const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options] ID [--] [CMD]", program);
+    let brief = format!(
+        "Usage: {0} [options] ID [--] [CMD]\n       {0} [options] --pod NAMESPACE/POD [-c NAME] [--] [CMD]\n       {0} [options] --bundle ID|PATH [--] [CMD]\n       {0} [options] [--all|--runtime NAME|--label KEY[=VALUE]] [ID...] -- CMD\n       {0} recover [ID]\n       {0} list|status [--json]\n       {0} gc [--dry-run]",
+        program
+    );
     print!("{}", opts.usage(&brief));
 }
 
 
-fn mount_image(image: &str, link: &str) -> Result<i32> {
-    // get free loop device
+/// An open loop device backed by the image. Devices are set up to clear
+/// themselves once they are no longer used, so the device is held open
+/// until it is mounted.
+struct LoopDevice {
+    devnr: i32,
+    fd: RawFd,
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+fn mount_image(image: &str, config: &loopback::Config) -> Result<LoopDevice> {
     let cfd = open("/dev/loop-control", OFlag::empty(), Mode::empty())
         .chain_err(|| "failed to open /dev/loop-control")?;
-    let devnr = loopback::loop_ctl_get_free(cfd).chain_err(
-        || "failed to get free device",
-    )?;
     defer!(close(cfd).unwrap());
 
-    // set backing file for loop device to image
-    let lp = format!("/dev/loop{}", devnr);
-    let lfd = open(&*lp, OFlag::empty(), Mode::empty()).chain_err(|| {
-        format!("failed to open {}", lp)
-    })?;
-    defer!(close(lfd).unwrap());
-
-    let ifd = open(image, OFlag::empty(), Mode::empty()).chain_err(|| {
+    let mode = if config.flags & loopback::LO_FLAGS_READ_ONLY != 0 {
+        O_RDONLY
+    } else {
+        O_RDWR
+    };
+    let ifd = open(image, mode, Mode::empty()).chain_err(|| {
         format!("failed to open {}", image)
     })?;
     defer!(close(ifd).unwrap());
 
-    loopback::loop_set_fd(lfd, ifd).chain_err(|| {
-        format!("failed to set backing file to {}", image)
-    })?;
+    let mut tries = 0;
+    let device = loop {
+        // get free loop device
+        let devnr = loopback::loop_ctl_get_free(cfd).chain_err(
+            || "failed to get free device",
+        )?;
+        let lp = format!("/dev/loop{}", devnr);
+        let lfd = open(&*lp, O_RDWR, Mode::empty()).chain_err(|| {
+            format!("failed to open {}", lp)
+        })?;
+        let device = LoopDevice { devnr, fd: lfd };
 
-    symlink(&lp, &link).chain_err(|| {
-        format!("failed to symlink from {} to {}", link, lp)
-    })?;
+        // set backing file for loop device to image
+        match loopback::loop_configure(lfd, ifd, config) {
+            Ok(()) => break device,
+            // someone else took the device in the meantime
+            Err(e) if e.errno() == Errno::EBUSY && tries < 5 => tries += 1,
+            Err(e) => {
+                return Err(e).chain_err(|| {
+                    format!("failed to set backing file to {}", image)
+                })
+            }
+        }
+    };
+
+    info!("backed /dev/loop{} to {}", device.devnr, image);
+    Ok(device)
+}
+
+/// Returns an open loop device that is attached read only to image at
+/// offset, if there is one. Devices are matched by the device and inode of
+/// their backing file, as its path may be different in other namespaces.
+fn find_device(image: &str, offset: u64) -> Result<Option<LoopDevice>> {
+    let meta = match metadata(image) {
+        Ok(m) => m,
+        Err(_) => return Ok(None),
+    };
+    let entries = read_dir("/sys/block").chain_err(|| "failed to read /sys/block")?;
+    for entry in entries {
+        let entry = entry.chain_err(|| "failed to read /sys/block")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let devnr = match name.strip_prefix("loop").map(|n| n.parse::<i32>()) {
+            Some(Ok(n)) => n,
+            _ => continue,
+        };
+        // only attached devices have a backing file
+        if metadata(entry.path().join("loop/backing_file")).is_err() {
+            continue;
+        }
+        // open the device before checking it, so that it can not clear
+        // itself in between
+        let lp = format!("/dev/loop{}", devnr);
+        let device = match open(&*lp, O_RDONLY, Mode::empty()) {
+            Ok(fd) => LoopDevice { devnr, fd },
+            Err(_) => continue,
+        };
+        let status = match loopback::loop_get_status64(device.fd) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        if status.device == meta.dev() && status.inode == meta.ino() &&
+            status.offset == offset &&
+            status.flags & loopback::LO_FLAGS_READ_ONLY != 0
+        {
+            return Ok(Some(device));
+        }
+    }
+    Ok(None)
+}
 
-    info!("backed /dev/loop{} to {}", devnr, image);
-    Ok(devnr)
+/// Detaches the loop device from its image, or, if it is still mounted
+/// somewhere, makes it detach itself once it is unmounted everywhere.
+fn clear_device(devnr: i32) -> Result<()> {
+    let lp = format!("/dev/loop{}", devnr);
+    let lfd = open(&*lp, O_RDONLY, Mode::empty()).chain_err(|| {
+        format!("failed to open {}", lp)
+    })?;
+    defer!(close(lfd).unwrap());
+    match loopback::loop_clr_fd(lfd) {
+        Ok(()) => Ok(()),
+        // not attached (anymore)
+        Err(e) if e.errno() == Errno::ENXIO => Ok(()),
+        Err(e) => Err(e).chain_err(|| format!("failed to clear {}", lp)),
+    }
 }
 
 macro_rules! maybe {
@@ -89,18 +192,8 @@ macro_rules! maybe {
     });
 }
 
-fn is_backing(devnr: i32, image: &str) -> bool {
-    let path = format!("/sys/block/loop{}/loop/backing_file", devnr);
-    let mut f = maybe!(File::open(path));
-    let mut backing = String::new();
-    maybe!(f.read_to_string(&mut backing));
-    let image_path = maybe!(canonicalize(&image));
-    let backing_path = maybe!(canonicalize(&backing.trim()));
-    image_path == backing_path
-}
-
-fn is_readonly_dev(pid: u64) -> bool {
-    let path = format!("/proc/{}/mounts", pid);
+fn is_readonly_dev(target: &Process) -> bool {
+    let path = target.proc_path("mounts");
     let f = maybe!(File::open(path));
     for line in BufReader::new(f).lines() {
         let l = match line {
@@ -123,9 +216,12 @@ fn is_readonly_dev(pid: u64) -> bool {
     false
 }
 
-fn make_device(image: &str) -> Result<i32> {
+fn make_device(image: &str, config: &loopback::Config) -> Result<LoopDevice> {
     // create lock file
-    let lockp = format!("{}.lock", image);
+    create_dir_all(CC_STATE_DIR).chain_err(|| {
+        format!("failed to create {}", CC_STATE_DIR)
+    })?;
+    let lockp = format!("{}/loop.lock", CC_STATE_DIR);
     let lockfd = open(&*lockp, O_RDWR | O_CREAT, Mode::from_bits_truncate(0o644))
         .chain_err(|| format!("failed to open {}", lockp))?;
     defer!(close(lockfd).unwrap());
@@ -133,74 +229,144 @@ fn make_device(image: &str) -> Result<i32> {
     flock(lockfd, FlockArg::LockExclusive).chain_err(|| {
         format!("could not get lock on {}", lockp)
     })?;
+    // the lock file is left in place, removing it would let another
+    // process lock a new file while this one still holds the old one
     defer!(flock(lockfd, FlockArg::Unlock).unwrap());
-    defer!(remove_file(&lockp).unwrap());
-
-    let link = format!("{}.link", image);
-    match read_link(&link) {
-        Ok(m) => {
-            let devnr = m.to_str().unwrap()["/dev/loop".len()..]
-                .parse::<i32>()
-                .unwrap();
-            if !is_backing(devnr, image) {
-                remove_file(&link).chain_err(
-                    || format!("could not delete {}", link),
-                )?;
-                return mount_image(image, &link);
-            };
-            info!("{} is backed to /dev/loop{}", image, devnr);
-            Ok(devnr)
+
+    if let Some(device) = find_device(image, config.offset)? {
+        info!("{} is backed to /dev/loop{}", image, device.devnr);
+        return Ok(device);
+    }
+    mount_image(image, config)
+}
+
+fn is_tty() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::isatty(1) == 1 }
+}
+
+fn pick(id: &str, candidates: &[Candidate]) -> Result<Candidate> {
+    println!("id {} is ambiguous:", id);
+    for (i, c) in candidates.iter().enumerate() {
+        println!("{:>3}) {}", i + 1, c);
+    }
+    loop {
+        print!("select a container [1-{}]: ", candidates.len());
+        stdout().flush().chain_err(|| "failed to flush stdout")?;
+        let mut line = String::new();
+        if stdin().read_line(&mut line).chain_err(
+            || "failed to read selection",
+        )? == 0
+        {
+            bail!(ErrorKind::AmbiguousId(id.to_string(), candidates.to_vec()));
         }
-        Err(e) => {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                let msg = format!("could not read {}", image);
-                Err(e).chain_err(|| msg)
-            } else {
-                mount_image(image, &link)
-            }
+        match line.trim().parse::<usize>() {
+            Ok(i) if i >= 1 && i <= candidates.len() => return Ok(candidates[i - 1].clone()),
+            _ => println!("invalid selection {}", line.trim()),
         }
     }
 }
 
-const PID_GLOBS: &'static [&'static str] = &[
-    "/var/run/docker/libcontainerd/containerd/{}*/init/pid",
-    "/var/lib/rkt/pods/run/{}*/pid",
-];
-
-fn get_pid(id: &str) -> Result<u64> {
-    // NOTE: An alternative option for finding docker pids is to find the
-    // docker cgroup hierarchy and read the first pid in the tasks file
-    // tasks file associated with he container id, for example:
-    // /sys/fs/cgroup/memory/docker/*/tasks
-    let mut out = id.to_owned();
-    let mut pid_file = String::new();
-    for entry in PID_GLOBS {
-        let results = glob(&entry.replace("{}", id))
-            .chain_err(|| format!("invalid glob for id {}", id))?
-            .map(|s| s.unwrap().to_str().unwrap().to_string())
-            .collect::<Vec<String>>();
-        match results.len() {
-            0 => (),
-            1 => pid_file = results[0].to_owned(),
-            _ => bail!("id {} is ambiguous", id),
-        }
-    }
-    if !pid_file.is_empty() {
-        info!("Found pid_file at {}", pid_file);
-        let mut f = File::open(&pid_file).chain_err(|| {
-            format!("could not open {}", pid_file)
-        })?;
-        out = String::new();
-        f.read_to_string(&mut out).chain_err(|| {
-            format!("could not read {}", pid_file)
-        })?;
+/// Returns the pid of the container with id, as found by resolvers. Unless
+/// waiting, id may also be a pid. Returns None if there is no such
+/// container. If the id is ambiguous, the container is picked from a list
+/// when interactive and run from a terminal.
+fn resolve_pid<'a, I>(resolvers: I, id: &str, wait: bool, interactive: bool) -> Result<Option<u64>>
+where
+    I: IntoIterator<Item = &'a dyn RuntimeResolver>,
+{
+    // pid:N is always a pid, even if it looks like a container id
+    if let Some(pid) = id.strip_prefix("pid:") {
+        return pid.parse::<u64>().map(Some).chain_err(
+            || format!("{} is not a valid pid", pid),
+        );
+    }
+    let found = match runtime::find(resolvers, id) {
+        Err(Error(ErrorKind::AmbiguousId(_, ref candidates), _)) if interactive && is_tty() => {
+            Some(pick(id, candidates)?)
+        }
+        result => result?,
+    };
+    if let Some(c) = found {
+        info!("found {} container {} with pid {}", c.runtime, c.id, c.pid);
+        return Ok(Some(c.pid));
     }
-    out.parse::<u64>().chain_err(
-        || format!("{} is not a valid pid", out),
+    // when waiting, a number is taken to be the id of a container that
+    // has not started yet
+    if wait {
+        return Ok(None);
+    }
+    id.parse::<u64>().map(Some).chain_err(
+        || format!("{} is not a valid pid", id),
     )
 }
 
-const NAMESPACES: &[(CloneFlags, &'static str)] = &[
+fn lookup_pid(
+    resolvers: &[Box<dyn RuntimeResolver>],
+    id: &str,
+    wait: bool,
+    interactive: bool,
+) -> Result<Option<u64>> {
+    // an id of outer/inner is a container inside another container, so
+    // inner is looked up in the runtime state of outer
+    let mut parts = id.split('/');
+    let outer = parts.next().unwrap();
+    let mut pid = match resolve_pid(resolvers.iter().map(|r| &**r), outer, wait, interactive)? {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    for inner in parts {
+        let exit_root = enter_root(&format!("/proc/{}/root", pid))?;
+        let inner_pid = resolve_pid(runtime::nested(resolvers), inner, wait, interactive);
+        exit_root()?;
+        pid = match inner_pid? {
+            Some(p) => process::host_pid(pid, p)?,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(pid))
+}
+
+fn get_pid(id: &str, interactive: bool) -> Result<u64> {
+    match lookup_pid(&runtime::resolvers(), id, false, interactive)? {
+        Some(pid) => Ok(pid),
+        None => bail!("no container {}", id),
+    }
+}
+
+/// Calls find until it returns a result whose pid is running, checking
+/// again whenever the runtimes write new state. Errors are treated as the
+/// container not being there yet, except for ambiguous ids which would
+/// never resolve.
+fn wait_for<T, F, P>(what: &str, mut find: F, pid: P) -> Result<T>
+where
+    F: FnMut(&[Box<dyn RuntimeResolver>]) -> Result<Option<T>>,
+    P: Fn(&T) -> u64,
+{
+    let resolvers = runtime::resolvers();
+    let mut watcher = wait::Watcher::new(&runtime::watch_paths(&resolvers))?;
+    info!("waiting for {}", what);
+    loop {
+        match find(&resolvers) {
+            // the state of the last run of a crash looping container can
+            // still be there, with a pid that is gone by now
+            Ok(Some(found)) => {
+                let p = pid(&found);
+                if process::start_time(p).is_ok() {
+                    return Ok(found);
+                }
+                debug!("{} not found yet: pid {} is gone", what, p);
+            }
+            Ok(None) => {}
+            Err(e @ Error(ErrorKind::AmbiguousId(..), _)) => return Err(e),
+            Err(e) => debug!("{} not found yet: {}", what, e),
+        }
+        // the timeout covers state that is not watched, such as cgroups
+        // created deeper in the tree
+        watcher.wait(100)?;
+    }
+}
+
+const NAMESPACES: &[(CloneFlags, &str)] = &[
     (CLONE_NEWIPC, "ipc"),
     (CLONE_NEWUTS, "uts"),
     (CLONE_NEWNET, "net"),
@@ -210,11 +376,21 @@ const NAMESPACES: &[(CloneFlags, &'static str)] = &[
     (CLONE_NEWUSER, "user"),
 ];
 
-fn enter_namespaces(pid: u64, namespaces: CloneFlags) -> Result<()> {
+fn enter_namespaces(target: &Process, namespaces: CloneFlags) -> Result<()> {
+    let to_enter = open_namespaces(target, namespaces)?;
+    defer!(for &(_, fd) in &to_enter {
+        close(fd).unwrap();
+    });
+    join_namespaces(target, &to_enter)
+}
+
+/// Opens the namespaces of the target that differ from ours, so that they
+/// can be joined later on, even if /proc of the host is gone by then.
+fn open_namespaces(target: &Process, namespaces: CloneFlags) -> Result<Vec<(CloneFlags, RawFd)>> {
     let mut to_enter = Vec::new();
     for &(space, name) in NAMESPACES {
         if namespaces.contains(space) {
-            debug!("entering {} namespace of {}", name, pid);
+            debug!("entering {} namespace of {}", name, target.pid());
             let oldpath = format!("/proc/self/ns/{}", name);
             let oldfd = match open(&*oldpath, OFlag::empty(), Mode::empty()) {
                 Err(e) => {
@@ -222,13 +398,13 @@ fn enter_namespaces(pid: u64, namespaces: CloneFlags) -> Result<()> {
                         continue;
                     }
                     let msg = format!("failed to open {}", oldpath);
-                    return Err(e).chain_err(|| msg)?;
+                    return Err(e).chain_err(|| msg);
                 }
                 Ok(fd) => fd,
             };
             let stat = fstat(oldfd).chain_err(|| "failed to stat")?;
             close(oldfd).unwrap();
-            let newpath = format!("/proc/{}/ns/{}", pid, name);
+            let newpath = target.proc_path(&format!("ns/{}", name));
             let fd = match open(&*newpath, OFlag::empty(), Mode::empty()) {
                 Err(e) => {
                     if e.errno() == Errno::ENOENT {
@@ -247,9 +423,26 @@ fn enter_namespaces(pid: u64, namespaces: CloneFlags) -> Result<()> {
             }
         }
     }
-    for &(space, fd) in &to_enter {
-        setns(fd, space).chain_err(|| "failed to enter")?;
-        close(fd).unwrap();
+    // make sure the namespace files belong to the target before using them
+    if let Err(e) = target.check() {
+        for &(_, fd) in &to_enter {
+            close(fd).unwrap();
+        }
+        return Err(e);
+    }
+    Ok(to_enter)
+}
+
+fn join_namespaces(target: &Process, to_enter: &[(CloneFlags, RawFd)]) -> Result<()> {
+    let spaces = to_enter.iter().fold(CloneFlags::empty(), |s, &(space, _)| s | space);
+    if spaces.is_empty() {
+        return Ok(());
+    }
+    let entered = target.setns(spaces)?;
+    for &(space, fd) in to_enter {
+        if !entered {
+            setns(fd, space).chain_err(|| "failed to enter")?;
+        }
         if space == CLONE_NEWUSER {
             setresgid(0, 0, 0).chain_err(|| "failed to setgid")?;
             setresuid(0, 0, 0).chain_err(|| "failed to setuid")?;
@@ -258,7 +451,7 @@ fn enter_namespaces(pid: u64, namespaces: CloneFlags) -> Result<()> {
     Ok(())
 }
 
-fn enter_mount_ns(pid: u64) -> Result<Box<(Fn() -> Result<()>)>> {
+fn enter_mount_ns(target: &Process) -> Result<Box<dyn Fn() -> Result<()>>> {
     let origpath = "/proc/self/ns/mnt";
     let ofd = open(origpath, OFlag::empty(), Mode::empty()).chain_err(
         || {
@@ -268,7 +461,7 @@ fn enter_mount_ns(pid: u64) -> Result<Box<(Fn() -> Result<()>)>> {
 
     // enter ns and return closure to reset
     let cwd = env::current_dir().chain_err(|| "failed to get cwd")?;
-    enter_namespaces(pid, CLONE_NEWNS)?;
+    enter_namespaces(target, CLONE_NEWNS)?;
     Ok(Box::new(move || {
         setns(ofd, CLONE_NEWNS).chain_err(|| "failed to setns")?;
         close(ofd).chain_err(
@@ -279,7 +472,7 @@ fn enter_mount_ns(pid: u64) -> Result<Box<(Fn() -> Result<()>)>> {
     }))
 }
 
-fn enter_pid_ns(pid: u64) -> Result<Box<(Fn() -> Result<()>)>> {
+fn enter_pid_ns(target: &Process) -> Result<Box<dyn Fn() -> Result<()>>> {
     let origpath = "/proc/self/ns/pid";
     let ofd = open(origpath, OFlag::empty(), Mode::empty()).chain_err(
         || {
@@ -288,7 +481,7 @@ fn enter_pid_ns(pid: u64) -> Result<Box<(Fn() -> Result<()>)>> {
     )?;
 
     // enter ns and return closure to reset
-    enter_namespaces(pid, CLONE_NEWPID)?;
+    enter_namespaces(target, CLONE_NEWPID)?;
     Ok(Box::new(move || {
         setns(ofd, CLONE_NEWPID).chain_err(|| "failed to setns")?;
         close(ofd).chain_err(
@@ -298,6 +491,24 @@ fn enter_pid_ns(pid: u64) -> Result<Box<(Fn() -> Result<()>)>> {
     }))
 }
 
+fn enter_root(path: &str) -> Result<Box<dyn Fn() -> Result<()>>> {
+    let ofd = open("/", OFlag::empty(), Mode::empty()).chain_err(
+        || "failed to open /",
+    )?;
+
+    // chroot and return closure to reset
+    let cwd = env::current_dir().chain_err(|| "failed to get cwd")?;
+    chroot(path).chain_err(|| format!("failed to chroot to {}", path))?;
+    chdir("/").chain_err(|| "failed to chdir")?;
+    Ok(Box::new(move || {
+        fchdir(ofd).chain_err(|| "failed to fchdir")?;
+        chroot(".").chain_err(|| "failed to chroot")?;
+        close(ofd).chain_err(|| "failed to close /")?;
+        env::set_current_dir(&cwd).chain_err(|| "failed to set cwd")?;
+        Ok(())
+    }))
+}
+
 fn find_root(path: &str) -> Result<u32> {
     let mut file = match File::open(path) {
         Err(e) => {
@@ -312,22 +523,31 @@ fn find_root(path: &str) -> Result<u32> {
     let mut contents = String::new();
     file.read_to_string(&mut contents).unwrap();
     for line in contents.split('\n') {
-        let words: Vec<&str> = line.trim().split_whitespace().collect();
+        let words: Vec<&str> = line.split_whitespace().collect();
         if words.len() < 2 {
             continue;
         }
         if words[0] == "0" {
-            return Ok(
-                words[1].parse::<u32>().chain_err(|| "failed to parse root")?,
-            );
+            return words[1].parse::<u32>().chain_err(|| "failed to parse root");
         }
     }
     Ok(0)
 }
 
-fn set_fsids(pid: u64) -> Result<Box<(Fn() -> ())>> {
-    let uid = find_root(&format!("/proc/{}/uid_map", pid))?;
-    let gid = find_root(&format!("/proc/{}/gid_map", pid))?;
+/// Returns the host uid and gid of root in the user namespace of target.
+fn root_ids(target: &Process) -> Result<(u32, u32)> {
+    let uid = find_root(&target.proc_path("uid_map"))?;
+    let gid = find_root(&target.proc_path("gid_map"))?;
+    target.check()?;
+    Ok((uid, gid))
+}
+
+fn set_fsids(target: &Process) -> Result<Box<dyn Fn()>> {
+    let (uid, gid) = root_ids(target)?;
+    set_fsids_to(uid, gid)
+}
+
+fn set_fsids_to(uid: u32, gid: u32) -> Result<Box<dyn Fn()>> {
     if uid == 0 && gid == 0 {
         return Ok(Box::new(|| {}));
     }
@@ -384,94 +604,338 @@ fn is_mounted(path: &str) -> Result<bool> {
     Ok(stat.st_dev != pstat.st_dev)
 }
 
-const CC_LOOP_TMP: &'static str = "/dev/cc-loop";
-const CC_MOUNT_PATH: &'static str = "/dev/crashcart";
+const CC_MOUNT_PATH: &str = "/dev/crashcart";
+// where to mount the tools if /dev can not be used, in order
+const CC_MOUNT_FALLBACKS: &[&str] = &["/run/crashcart-tools", "/tmp/.crashcart"];
+// created next to the mount point to hold the loop device node
+const CC_LOOP_DIR: &str = "cc-loop";
+// the name loop devices are set up with, so that gc only touches ours
+const CC_LOOP_NAME: &str = "crashcart";
+// all state is kept here, so that nothing is written next to the image
+const CC_STATE_DIR: &str = "/run/crashcart";
+const CC_STAGING: &str = "/run/crashcart/staging";
 
-fn do_mount(pid: u64, image: &str) -> Result<()> {
-    let devnr = make_device(image)?;
-    // if we are in a userns, make sure that we have the right fsids
-    let reset_fsids = set_fsids(pid)?;
-    defer!(reset_fsids());
-    let readonly = is_readonly_dev(pid);
-    let exit_mount_ns = enter_mount_ns(pid)?;
-    defer!(exit_mount_ns().unwrap());
+/// Remounts /dev read/write, leaving a sentinel so that it is made read only
+/// again on unmount.
+fn make_dev_writable() -> Result<()> {
+    // TODO: The userns should be entered first so don't mess up the
+    //       permissions on remount.
+    if let Err(e) = mount(
+        Some("/dev"),
+        "/dev",
+        None::<&str>,
+        MS_REMOUNT | MS_NOSUID | MS_STRICTATIME,
+        None::<&str>,
+    )
+    {
+        if e.errno() != Errno::EBUSY {
+            warn!("could not remount dev read/write");
+        }
+    }
+    let sentinel = "/dev/readonly";
+    let fd = open(sentinel, O_RDWR | O_CREAT, Mode::from_bits_truncate(0o644))
+        .chain_err(|| format!("failed to open {}", sentinel))?;
+    close(fd).unwrap();
+    Ok(())
+}
 
-    if readonly {
-        // TODO: The userns should be entered first so don't mess up the
-        //       permissions on remount.
-        if let Err(e) = mount(
-            Some("/dev"),
-            "/dev",
-            None::<&str>,
-            MS_REMOUNT | MS_NOSUID | MS_STRICTATIME,
-            None::<&str>,
-        )
-        {
-            if e.errno() != Errno::EBUSY {
-                warn!("could not remount dev read/write");
+/// Returns a detached, read only copy of the mount tree at dir.
+fn dir_tree(dir: &str) -> Result<RawFd> {
+    let tree = match mount_api::clone_tree(dir) {
+        Ok(fd) => fd,
+        Err(e) => {
+            if e.errno() == Errno::ENOSYS {
+                bail!("--dir needs open_tree (linux 5.2 or later)");
             }
+            return Err(e).chain_err(|| format!("failed to clone mounts at {}", dir));
         }
-        let sentinel = "/dev/readonly";
-        let fd = open(sentinel, O_RDWR | O_CREAT, Mode::from_bits_truncate(0o644))
-            .chain_err(|| format!("failed to open {}", sentinel))?;
-        close(fd).unwrap();
-
+    };
+    let attrs = mount_api::MOUNT_ATTR_RDONLY | mount_api::MOUNT_ATTR_NOSUID |
+        mount_api::MOUNT_ATTR_NODEV;
+    if let Err(e) = mount_api::set_attrs(tree, attrs) {
+        close(tree).unwrap();
+        if e.errno() == Errno::ENOSYS {
+            bail!("--dir needs mount_setattr (linux 5.12 or later)");
+        }
+        return Err(e).chain_err(|| format!("failed to make {} read only", dir));
     }
+    Ok(tree)
+}
 
-    // NOTE: the default dev device inside a user namespace can not hold
-    //       loopback devices, so we create a new tmpfs mount from the
-    //       init_user_ns to hold the device
-    if !is_mounted(CC_LOOP_TMP)? {
-        create_dir_all(CC_LOOP_TMP).chain_err(|| {
-            format!("failed to create {}", CC_LOOP_TMP)
-        })?;
-        if let Err(e) = mount(
+/// Returns a detached copy of a mount of the image backed by loop device
+/// devnr, or None if the kernel is too old to move mounts between
+/// namespaces. The image is mounted on the host below a private tmpfs, so
+/// that the mount does not propagate anywhere else, and only until it is
+/// copied, so that the loop device clears itself once the last copy is
+/// unmounted.
+fn image_tree(image: &str, devnr: i32, fstype: &str) -> Result<Option<RawFd>> {
+    create_dir_all(CC_STAGING).chain_err(|| {
+        format!("failed to create {}", CC_STAGING)
+    })?;
+    let lockfd = open(CC_STAGING, O_DIRECTORY, Mode::empty()).chain_err(|| {
+        format!("failed to open {}", CC_STAGING)
+    })?;
+    defer!(close(lockfd).unwrap());
+    flock(lockfd, FlockArg::LockExclusive).chain_err(|| {
+        format!("could not get lock on {}", CC_STAGING)
+    })?;
+    defer!(flock(lockfd, FlockArg::Unlock).unwrap());
+
+    if !is_mounted(CC_STAGING)? {
+        mount(
             Some("tmpfs"),
-            CC_LOOP_TMP,
+            CC_STAGING,
             Some("tmpfs"),
             MsFlags::empty(),
-            None::<&str>,
-        )
-        {
-            if e.errno() != Errno::EBUSY {
-                let msg = format!("could not mount tmpfs to {}", CC_LOOP_TMP);
-                Err(e).chain_err(|| msg)?;
+            Some("mode=0700"),
+        ).chain_err(|| format!("could not mount tmpfs to {}", CC_STAGING))?;
+        mount(None::<&str>, CC_STAGING, None::<&str>, MS_PRIVATE, None::<&str>)
+            .chain_err(|| format!("could not make {} private", CC_STAGING))?;
+    }
+    let staged = format!("{}/loop{}", CC_STAGING, devnr);
+    if !is_mounted(&staged)? {
+        create_dir_all(&staged).chain_err(
+            || format!("failed to create {}", staged),
+        )?;
+        let device = format!("/dev/loop{}", devnr);
+        mount(Some(&*device), &*staged, Some(fstype), MS_RDONLY, None::<&str>)
+            .chain_err(|| {
+                format!("could not mount {} ({}) to {}", device, fstype, staged)
+            })?;
+        debug!("mounted {} at {}", image, staged);
+    }
+    defer!(if let Err(e) = umount(&*staged) {
+        warn!("could not unmount {}: {}", staged, e);
+    });
+    match mount_api::clone_tree(&*staged) {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) => {
+            if e.errno() == Errno::ENOSYS {
+                debug!("open_tree is not supported, mounting the device instead");
+                return Ok(None);
             }
+            Err(e).chain_err(|| format!("failed to clone mount at {}", staged))
         }
     }
-    let ccimage = format!("{}/loop{}", CC_LOOP_TMP, devnr);
-    if let Err(e) = mknod(
-        &*ccimage,
-        S_IFBLK,
-        Mode::from_bits_truncate(0o660),
-        loopback::loopdev(devnr),
-    )
-    {
-        if e.errno() != Errno::EEXIST {
-            let msg = format!("could not mknod {}", ccimage);
-            Err(e).chain_err(|| msg)?;
+}
+
+/// Returns the paths to try mounting the tools at, in order.
+fn mount_paths(matches: &getopts::Matches) -> Vec<String> {
+    match matches.opt_str("mount-path") {
+        Some(path) => vec![path],
+        None => {
+            let mut paths = vec![CC_MOUNT_PATH.to_string()];
+            paths.extend(CC_MOUNT_FALLBACKS.iter().map(|p| p.to_string()));
+            paths
         }
     }
-    if !is_mounted(CC_MOUNT_PATH)? {
-        create_dir_all(CC_MOUNT_PATH).chain_err(|| {
-            format!("failed to create {}", CC_MOUNT_PATH)
-        })?;
+}
+
+/// Warns when an image is not loaded at the first of paths, as the
+/// binaries in images built by build_image.sh only work at /dev/crashcart.
+fn warn_fallback(image: &str, paths: &[String], path: &str) {
+    if path != paths[0] {
+        warn!(
+            "{} is loaded at {} as {} could not be used, images built by build_image.sh only work at {}",
+            image,
+            path,
+            paths[0],
+            CC_MOUNT_PATH
+        );
+    }
+}
+
+/// Returns the directory that holds the loop device node for the tools
+/// mounted at path.
+fn loop_dir(path: &str) -> String {
+    let parent = Path::new(path).parent().unwrap_or_else(|| Path::new("/"));
+    parent.join(CC_LOOP_DIR).to_string_lossy().into_owned()
+}
+
+/// Creates path and any of its parents that are missing, outermost first,
+/// journaling each directory before it is made so that all of them can be
+/// removed again. The directories are added to created as they are made.
+fn create_dirs(path: &str, journal: &Journal, created: &mut Vec<String>) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut dir = Path::new(path);
+    while !dir.exists() {
+        missing.push(dir.to_string_lossy().into_owned());
+        dir = match dir.parent() {
+            Some(p) => p,
+            None => break,
+        };
+    }
+    for dir in missing.into_iter().rev() {
+        journal.record(&Step::Mkdir(dir.clone()))?;
+        create_dir(&dir).chain_err(|| format!("failed to create {}", dir))?;
+        created.push(dir);
+    }
+    Ok(())
+}
+
+/// Mounts the tools with attach at the first of paths where it works and
+/// returns that path. A path where the tools are mounted already is used
+/// as it is. Has to be called in the mount namespace of the target.
+fn mount_first(
+    paths: &[String],
+    readonly_dev: bool,
+    journal: &Journal,
+    attach: &dyn Fn(&str) -> Result<()>,
+) -> Result<String> {
+    for path in paths {
+        if is_mounted(path)? {
+            return Ok(path.clone());
+        }
+        if readonly_dev && path.starts_with("/dev/") {
+            if let Err(e) = journal.record(&Step::Remount).and_then(|_| make_dev_writable()) {
+                warn!("could not use {}: {}", path, chain(&e));
+                continue;
+            }
+        }
+        let mut created = Vec::new();
+        let res = create_dirs(path, journal, &mut created).and_then(|_| attach(path));
+        match res {
+            Ok(()) => return Ok(path.clone()),
+            Err(e) => {
+                for dir in created.iter().rev() {
+                    let _ = remove_dir(dir);
+                }
+                warn!("could not use {}: {}", path, chain(&e));
+            }
+        }
+    }
+    bail!("could not mount the tools at {}", paths.join(", "))
+}
+
+/// Moves the detached mount tree to the first usable of paths in the
+/// target. As the mounts are set up on the host, what is mounted does not
+/// have to be visible in the container.
+fn attach_tree(
+    target: &Process,
+    tree: RawFd,
+    what: &str,
+    paths: &[String],
+    journal: &Journal,
+) -> Result<String> {
+    // if we are in a userns, make sure that we have the right fsids
+    let reset_fsids = set_fsids(target)?;
+    defer!(reset_fsids());
+    let readonly = is_readonly_dev(target);
+    let exit_mount_ns = enter_mount_ns(target)?;
+    defer!(exit_mount_ns().unwrap());
+
+    let path = mount_first(paths, readonly, journal, &|path| {
+        journal.record(&Step::Mount(path.to_string()))?;
+        mount_api::move_mount(tree, path).chain_err(|| {
+            format!("could not mount {} to {}", what, path)
+        })
+    })?;
+    info!("{} is loaded at {} in namespace of pid {}", what, path, target.pid());
+    Ok(path)
+}
+
+/// The tools as they are set up on the host: a detached mount tree, or
+/// on kernels without the new mount api, the loop device to mount and
+/// the filesystem type of the image.
+enum Source {
+    Tree(RawFd),
+    Device(LoopDevice, &'static str),
+}
+
+/// Sets up the image, or the host directory dir if given, to be mounted.
+fn tools_source(image: &str, config: &loopback::Config, dir: Option<&str>) -> Result<Source> {
+    if let Some(dir) = dir {
+        return Ok(Source::Tree(dir_tree(dir)?));
+    }
+    let fstype = image::fs_type(image, config.offset)?;
+    let device = make_device(image, config)?;
+    match image_tree(image, device.devnr, fstype)? {
+        Some(tree) => Ok(Source::Tree(tree)),
+        None => Ok(Source::Device(device, fstype)),
+    }
+}
+
+/// Mounts the image, or bind mounts the host directory dir if given, in
+/// the target and returns where.
+fn do_mount(
+    target: &Process,
+    image: &str,
+    config: &loopback::Config,
+    dir: Option<&str>,
+    paths: &[String],
+    journal: &Journal,
+) -> Result<String> {
+    let (device, fstype) = match tools_source(image, config, dir)? {
+        Source::Tree(tree) => {
+            defer!(close(tree).unwrap());
+            return attach_tree(target, tree, dir.unwrap_or(image), paths, journal);
+        }
+        Source::Device(device, fstype) => (device, fstype),
+    };
+    let devnr = device.devnr;
+
+    // older kernels can only mount the device itself in the namespace
+    // if we are in a userns, make sure that we have the right fsids
+    let reset_fsids = set_fsids(target)?;
+    defer!(reset_fsids());
+    let readonly = is_readonly_dev(target);
+    let exit_mount_ns = enter_mount_ns(target)?;
+    defer!(exit_mount_ns().unwrap());
+
+    let path = mount_first(paths, readonly, journal, &|path| {
+        // NOTE: the default dev device inside a user namespace can not hold
+        //       loopback devices, so we create a new tmpfs mount from the
+        //       init_user_ns to hold the device
+        let loop_tmp = loop_dir(path);
+        if !is_mounted(&loop_tmp)? {
+            create_dirs(&loop_tmp, journal, &mut Vec::new())?;
+            journal.record(&Step::Tmpfs(loop_tmp.clone()))?;
+            if let Err(e) = mount(
+                Some("tmpfs"),
+                &*loop_tmp,
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None::<&str>,
+            )
+            {
+                if e.errno() != Errno::EBUSY {
+                    let msg = format!("could not mount tmpfs to {}", loop_tmp);
+                    Err(e).chain_err(|| msg)?;
+                }
+            }
+        }
+        let ccimage = format!("{}/loop{}", loop_tmp, devnr);
+        journal.record(&Step::Mknod(ccimage.clone()))?;
+        if let Err(e) = mknod(
+            &*ccimage,
+            S_IFBLK,
+            Mode::from_bits_truncate(0o660),
+            loopback::loopdev(devnr),
+        )
+        {
+            if e.errno() != Errno::EEXIST {
+                let msg = format!("could not mknod {}", ccimage);
+                Err(e).chain_err(|| msg)?;
+            }
+        }
+        journal.record(&Step::Mount(path.to_string()))?;
         if let Err(e) = mount(
             Some(&*ccimage),
-            CC_MOUNT_PATH,
-            Some("ext3"),
+            path,
+            Some(fstype),
             MS_RDONLY,
             None::<&str>,
         )
         {
             if e.errno() != Errno::EBUSY {
-                let msg = format!("could not mount {} to {}", ccimage, CC_MOUNT_PATH);
+                let msg = format!("could not mount {} ({}) to {}", ccimage, fstype, path);
                 Err(e).chain_err(|| msg)?;
             }
         }
-    }
-    info!("{} is loaded into namespace of pid {}", image, pid);
-    Ok(())
+        Ok(())
+    })?;
+    info!("{} is loaded at {} in namespace of pid {}", image, path, target.pid());
+    Ok(path)
 }
 
 static mut CHILD_PID: i32 = 0;
@@ -486,148 +950,565 @@ extern "C" fn signal_handler(signo: c_int) {
     }
 }
 
-const DEFAULT_ARGS: &'static [&'static str] = &[
-    "/dev/crashcart/bin/bash",
-    "--rcfile",
-    "/dev/crashcart/.crashcartrc",
-    "-i",
-];
-
-fn do_exec(pid: u64, docker_id: &str, args: &[&str]) -> Result<i32> {
-    let a = if args.is_empty() {
-        &DEFAULT_ARGS[..]
+/// Returns the command to run, which is the shell from the tools mounted
+/// at path unless args are given.
+fn command(args: &[&str], path: &str) -> Vec<CString> {
+    let default = [
+        format!("{}/bin/bash", path),
+        "--rcfile".to_string(),
+        format!("{}/.crashcartrc", path),
+        "-i".to_string(),
+    ];
+    if args.is_empty() {
+        default.iter().map(|s| CString::new(s.as_str()).unwrap()).collect()
     } else {
-        args
-    };
-    if !docker_id.is_empty() {
-        let mut all = Vec::new();
-        all.push(CString::new("docker").unwrap());
-        all.push(CString::new("exec").unwrap());
-        all.push(CString::new("-it").unwrap());
-        all.push(CString::new(docker_id.to_string()).chain_err(
-            || "invalid docker id",
-        )?);
-        let mut other: Vec<CString> = a.iter()
-            .map(|s| CString::new(s.to_string()).unwrap())
-            .collect();
-        all.append(&mut other);
-        execvp(&all[0], &all).chain_err(|| "failed to exec")?;
+        args.iter().map(|s| CString::new(s.to_string()).unwrap()).collect()
     }
+}
 
-    // enter pid namespace before fork
-    let exit_pid_ns = enter_pid_ns(pid)?;
-
-    match fork().chain_err(|| "failed to fork")? {
-        ForkResult::Child => {
-            // enter remaining namespaces
-            enter_namespaces(
-                pid,
-                CLONE_NEWUSER | CLONE_NEWIPC | CLONE_NEWUTS | CLONE_NEWNS | CLONE_NEWCGROUP |
-                    CLONE_NEWNET,
-            )?;
-            // child execs parameters or execs docker_exec
-            let all: Vec<CString> = a.iter()
-                .map(|s| CString::new(s.to_string()).unwrap())
-                .collect();
-            execvp(&all[0], &all).chain_err(|| "failed to exec")?;
-            Ok(-1)
-        }
-        ForkResult::Parent { child } => {
-            // parent waits for child to exit, passing along signals
-            unsafe {
-                // NOTE: the child pid is only set once prior to setting up the
-                // signal handler, so it should be safe to access it from the
-                // signal handler.
-                CHILD_PID = child;
-                let a = SigAction::new(
-                    SigHandler::Handler(signal_handler),
-                    SaFlags::empty(),
-                    SigSet::all(),
-                );
-                sigaction(Signal::SIGTERM, &a).chain_err(
-                    || "failed to sigaction",
-                )?;
-                sigaction(Signal::SIGQUIT, &a).chain_err(
-                    || "failed to sigaction",
-                )?;
-                sigaction(Signal::SIGINT, &a).chain_err(
-                    || "failed to sigaction",
-                )?;
-                sigaction(Signal::SIGHUP, &a).chain_err(
-                    || "failed to sigaction",
-                )?;
-                sigaction(Signal::SIGUSR1, &a).chain_err(
-                    || "failed to sigaction",
-                )?;
-                sigaction(Signal::SIGUSR2, &a).chain_err(
-                    || "failed to sigaction",
-                )?;
-            }
-            let mut exit_code = -1;
-            while exit_code == -1 {
-                let result = match waitpid(child, None) {
-                    Err(e) => {
-                        // ignore EINTR as it gets sent when we get a SIGCHLD
-                        if e.errno() != Errno::EINTR {
-                            let msg = format!("could not waitpid on {}", child);
-                            Err(e).chain_err(|| msg)?;
-                        }
-                        WaitStatus::StillAlive
-                    }
-                    Ok(result) => result,
+/// The tools prepared on the host, ready to be mounted in another mount
+/// namespace.
+struct Tools {
+    attach: Box<Attach>,
+    /// the number of the loop device and the filesystem type of the image,
+    /// if the device itself has to be mounted, as the node may not exist
+    /// where attach is called
+    loop_mount: Option<(i32, &'static str)>,
+    /// keeps the loop device from clearing itself before it is mounted
+    _device: Option<LoopDevice>,
+    /// the image, or None for a directory, which works at any path
+    image: Option<String>,
+    /// the detached mount tree that attach moves, if any
+    tree: Option<RawFd>,
+    /// the image or directory, which sessions are keyed by
+    name: String,
+}
 
-                };
-                match result {
-                    WaitStatus::Exited(_, code) => exit_code = code as i32,
-                    WaitStatus::Signaled(_, signal, _) => exit_code = signal as i32 + 128,
-                    _ => (),
-                };
-            }
-            // reset pid namespace
-            exit_pid_ns().chain_err(|| "failed to exit pid ns")?;
-            Ok(exit_code)
+impl Drop for Tools {
+    fn drop(&mut self) {
+        if let Some(tree) = self.tree {
+            let _ = close(tree);
         }
     }
 }
 
-fn do_unmount_ns(pid: u64, devnr: i32) -> Result<()> {
-    // if we are in a userns, make sure that we have the right fsids
-    let reset_fsids = set_fsids(pid)?;
-    defer!(reset_fsids());
-    let exit_mount_ns = enter_mount_ns(pid)?;
-    defer!(exit_mount_ns().unwrap());
-
-    let ccimage = format!("{}/loop{}", CC_LOOP_TMP, devnr);
-    if let Err(e) = umount(CC_MOUNT_PATH) {
-        if e.errno() != Errno::ENOENT {
-            let msg = format!("could not unmount {} from {}", ccimage, CC_MOUNT_PATH);
-            Err(e).chain_err(|| msg)?;
+fn prepare_tools(image: &str, config: &loopback::Config, dir: Option<&str>) -> Result<Tools> {
+    let what = dir.unwrap_or(image).to_string();
+    let name = what.clone();
+    let image_name = match dir {
+        Some(_) => None,
+        None => Some(image.to_string()),
+    };
+    match tools_source(image, config, dir)? {
+        Source::Tree(tree) => {
+            Ok(Tools {
+                attach: Box::new(move |path: &Path| {
+                    mount_api::move_mount(tree, path).chain_err(|| {
+                        format!("could not mount {} to {:?}", what, path)
+                    })
+                }),
+                loop_mount: None,
+                _device: None,
+                image: image_name,
+                tree: Some(tree),
+                name,
+            })
         }
-    }
-    if let Err(e) = remove_dir(CC_MOUNT_PATH) {
-        if e.kind() != std::io::ErrorKind::NotFound {
-            let msg = format!("could not delete {}", CC_MOUNT_PATH);
-            Err(e).chain_err(|| msg)?;
+        Source::Device(device, fstype) => {
+            let lp = format!("/dev/loop{}", device.devnr);
+            Ok(Tools {
+                attach: Box::new(move |path: &Path| {
+                    mount(Some(&*lp), path, Some(fstype), MS_RDONLY, None::<&str>)
+                        .chain_err(|| format!("could not mount {} to {:?}", lp, path))
+                }),
+                loop_mount: Some((device.devnr, fstype)),
+                _device: Some(device),
+                image: image_name,
+                tree: None,
+                name,
+            })
         }
     }
-    if let Err(e) = remove_file(&ccimage) {
-        if e.kind() != std::io::ErrorKind::NotFound {
-            let msg = format!("could not delete {}", &ccimage);
-            Err(e).chain_err(|| msg)?;
+}
+
+/// Makes a private copy of the mount namespace of the target and mounts the
+/// tools at the first usable of paths in the copy only, which is returned.
+/// root holds the host ids of root in the container.
+fn mount_private(target: &Process, tools: &Tools, root: (u32, u32), paths: &[String]) -> Result<String> {
+    unshare(CLONE_NEWNS).chain_err(|| "failed to unshare mount namespace")?;
+    mount(None::<&str>, "/", None::<&str>, MS_REC | MS_PRIVATE, None::<&str>)
+        .chain_err(|| "failed to make / private")?;
+    for path in paths {
+        if is_mounted(path)? {
+            info!("{} is already mounted in the container", path);
+            return Ok(path.clone());
         }
-    }
-    if let Err(e) = umount(CC_LOOP_TMP) {
-        if e.errno() != Errno::ENOENT {
-            let msg = format!("could not unmount tmpfs from {}", CC_LOOP_TMP);
-            Err(e).chain_err(|| msg)?;
+        match shadow_mount(tools, root, path) {
+            Ok(()) => {
+                info!("tools are mounted privately at {} for pid {}", path, target.pid());
+                if let Some(ref image) = tools.image {
+                    warn_fallback(image, paths, path);
+                }
+                return Ok(path.clone());
+            }
+            Err(e) => warn!("could not use {}: {}", path, chain(&e)),
         }
     }
-    if let Err(e) = remove_dir(CC_LOOP_TMP) {
-        if e.kind() != std::io::ErrorKind::NotFound {
-            let msg = format!("could not delete {}", CC_LOOP_TMP);
-            Err(e).chain_err(|| msg)?;
+    bail!("could not mount the tools at {}", paths.join(", "))
+}
+
+/// Mounts the tools at path. The parent directory of path is shadowed by a
+/// tmpfs, owned by root, with bind mounts of everything that was in it, so
+/// that the mount point can be created without touching the container.
+fn shadow_mount(tools: &Tools, root: (u32, u32), path: &str) -> Result<()> {
+    let mount_point = Path::new(path);
+    let (parent, own) = match (mount_point.parent(), mount_point.file_name()) {
+        (Some(p), Some(n)) if p != Path::new("/") => (p, n),
+        _ => bail!("{} has no parent directory to shadow", path),
+    };
+    let parentfd = open(parent, O_DIRECTORY, Mode::empty()).chain_err(
+        || format!("failed to open {:?}", parent),
+    )?;
+    defer!(close(parentfd).unwrap());
+    let reset_fsids = set_fsids_to(root.0, root.1)?;
+    defer!(reset_fsids());
+    mount(
+        Some("tmpfs"),
+        parent,
+        Some("tmpfs"),
+        MS_NOSUID,
+        Some("mode=0755"),
+    ).chain_err(|| format!("could not mount tmpfs to {:?}", parent))?;
+    // the tmpfs hides the directory, so it may not stay if anything fails
+    let res = (|| -> Result<()> {
+        // the container may have no node for the loop device, so it gets
+        // one in the tmpfs, next to the mount point
+        let loop_tmp = loop_dir(path);
+        let loop_own = Path::new(&loop_tmp).file_name();
+        // the old directory is only reachable relative to its fd now
+        fchdir(parentfd).chain_err(|| format!("failed to fchdir to {:?}", parent))?;
+        defer!(chdir("/").unwrap());
+        for entry in read_dir(".").chain_err(|| format!("failed to read {:?}", parent))? {
+            let entry = entry.chain_err(|| format!("failed to read {:?}", parent))?;
+            let name = entry.file_name();
+            if name == own || (tools.loop_mount.is_some() && loop_own == Some(&*name)) {
+                continue;
+            }
+            let path = parent.join(&name);
+            let kind = entry.file_type().chain_err(
+                || format!("failed to stat {:?}", path),
+            )?;
+            if kind.is_symlink() {
+                let link = read_link(entry.path()).chain_err(
+                    || format!("failed to read {:?}", path),
+                )?;
+                symlink(&link, &path).chain_err(
+                    || format!("failed to symlink {:?}", path),
+                )?;
+                continue;
+            }
+            if kind.is_dir() {
+                create_dir(&path).map(drop)
+            } else {
+                File::create(&path).map(drop)
+            }.chain_err(|| format!("failed to create {:?}", path))?;
+            mount(
+                Some(&*entry.path()),
+                &*path,
+                None::<&str>,
+                MS_BIND | MS_REC,
+                None::<&str>,
+            ).chain_err(|| format!("could not bind mount {:?}", path))?;
+        }
+
+        create_dir(mount_point).chain_err(
+            || format!("failed to create {}", path),
+        )?;
+        let (devnr, fstype) = match tools.loop_mount {
+            Some(m) => m,
+            None => return (tools.attach)(mount_point),
+        };
+        create_dir(&loop_tmp).chain_err(
+            || format!("failed to create {}", loop_tmp),
+        )?;
+        let node = format!("{}/loop{}", loop_tmp, devnr);
+        mknod(
+            &*node,
+            S_IFBLK,
+            Mode::from_bits_truncate(0o660),
+            loopback::loopdev(devnr),
+        ).chain_err(|| format!("could not mknod {}", node))?;
+        mount(Some(&*node), mount_point, Some(fstype), MS_RDONLY, None::<&str>)
+            .chain_err(|| format!("could not mount {} to {}", node, path))
+    })();
+    if res.is_err() {
+        let _ = umount2(parent, MNT_DETACH);
+    }
+    res
+}
+
+/// Runs args (or the shell of the tools) in the target. Unless private is
+/// set, the tools are mounted already, at the first of paths. Otherwise
+/// they are mounted in a private copy of the mount namespace at the first
+/// of paths that works.
+fn do_exec(
+    target: &Process,
+    docker_id: &str,
+    args: &[&str],
+    paths: &[String],
+    private: Option<&Tools>,
+) -> Result<i32> {
+    if !docker_id.is_empty() {
+        let mut all = vec![
+            CString::new("docker").unwrap(),
+            CString::new("exec").unwrap(),
+            CString::new("-it").unwrap(),
+            CString::new("-e").unwrap(),
+            CString::new(format!("CRASHCART={}", paths[0])).chain_err(
+                || "invalid mount path",
+            )?,
+            CString::new(docker_id.to_string()).chain_err(
+                || "invalid docker id",
+            )?,
+        ];
+        let mut other = command(args, &paths[0]);
+        all.append(&mut other);
+        execvp(&all[0], &all).chain_err(|| "failed to exec")?;
+    }
+
+    // a private copy of the mount namespace is made by the child, so its
+    // session is registered once the child reports that the tools are in
+    let registry = match private {
+        Some(_) => Some(session::Registry::lock()?),
+        None => None,
+    };
+    let (mounted_r, mounted_w) = pipe2(O_CLOEXEC).chain_err(|| "failed to create pipe")?;
+
+    // enter pid namespace before fork
+    let exit_pid_ns = enter_pid_ns(target)?;
+
+    match fork().chain_err(|| "failed to fork")? {
+        ForkResult::Child => {
+            close(mounted_r).unwrap();
+            // enter remaining namespaces
+            let spaces = CLONE_NEWIPC | CLONE_NEWUTS | CLONE_NEWNS | CLONE_NEWCGROUP |
+                CLONE_NEWNET;
+            let path = match private {
+                Some(tools) => {
+                    // the copy of the mount namespace has to be made while
+                    // still privileged on the host, and /proc of the host is
+                    // hidden from then on, so read what is needed first
+                    let root = root_ids(target)?;
+                    let userns = open_namespaces(target, CLONE_NEWUSER)?;
+                    defer!(for &(_, fd) in &userns {
+                        close(fd).unwrap();
+                    });
+                    enter_namespaces(target, spaces)?;
+                    let path = mount_private(target, tools, root, paths)?;
+                    report_mounted(mounted_w, &path)?;
+                    join_namespaces(target, &userns)?;
+                    path
+                }
+                None => {
+                    enter_namespaces(target, spaces | CLONE_NEWUSER)?;
+                    paths[0].clone()
+                }
+            };
+            // the rcfile of the tools finds them through CRASHCART
+            env::set_var("CRASHCART", &path);
+            let all = command(args, &path);
+            execvp(&all[0], &all).chain_err(|| "failed to exec")?;
+            Ok(-1)
+        }
+        ForkResult::Parent { child } => {
+            close(mounted_w).unwrap();
+            defer!(close(mounted_r).unwrap());
+            let session = match (registry, private) {
+                (Some(registry), Some(tools)) => register_child(&registry, child, &tools.name, mounted_r)?,
+                _ => None,
+            };
+            let exit_code = wait_child(child)?;
+            drop(session);
+            // reset pid namespace
+            exit_pid_ns().chain_err(|| "failed to exit pid ns")?;
+            Ok(exit_code)
+        }
+    }
+}
+
+/// Tells the parent on fd that the tools are mounted at path. The parent
+/// reads until the child execs, which closes fd.
+fn report_mounted(fd: RawFd, path: &str) -> Result<()> {
+    write(fd, path.as_bytes()).chain_err(|| "failed to write to pipe")?;
+    close(fd).chain_err(|| "failed to close pipe")
+}
+
+/// Registers a session of the tools in the mount namespace of child, once
+/// the child has reported where it mounted them on fd. Returns None if the
+/// child is gone before it mounted them.
+fn register_child(
+    registry: &session::Registry,
+    child: libc::pid_t,
+    tools: &str,
+    fd: RawFd,
+) -> Result<Option<session::Session>> {
+    let mut path = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        match read(fd, &mut buf).chain_err(|| "failed to read from pipe")? {
+            0 => break,
+            n => path.extend_from_slice(&buf[..n]),
+        }
+    }
+    if path.is_empty() {
+        return Ok(None);
+    }
+    let target = match Process::open(child as u64) {
+        Ok(t) => t,
+        Err(_) => return Ok(None),
+    };
+    let key = session::Key::new(&target, tools)?;
+    Ok(Some(registry.register(&key, &String::from_utf8_lossy(&path))?))
+}
+
+/// Waits for child to exit, passing along signals, and returns its exit
+/// code.
+fn wait_child(child: libc::pid_t) -> Result<i32> {
+    unsafe {
+        // NOTE: the child pid is only set once prior to setting up the
+        // signal handler, so it should be safe to access it from the
+        // signal handler.
+        CHILD_PID = child;
+        let a = SigAction::new(
+            SigHandler::Handler(signal_handler),
+            SaFlags::empty(),
+            SigSet::all(),
+        );
+        sigaction(Signal::SIGTERM, &a).chain_err(
+            || "failed to sigaction",
+        )?;
+        sigaction(Signal::SIGQUIT, &a).chain_err(
+            || "failed to sigaction",
+        )?;
+        sigaction(Signal::SIGINT, &a).chain_err(
+            || "failed to sigaction",
+        )?;
+        sigaction(Signal::SIGHUP, &a).chain_err(
+            || "failed to sigaction",
+        )?;
+        sigaction(Signal::SIGUSR1, &a).chain_err(
+            || "failed to sigaction",
+        )?;
+        sigaction(Signal::SIGUSR2, &a).chain_err(
+            || "failed to sigaction",
+        )?;
+    }
+    let mut exit_code = -1;
+    while exit_code == -1 {
+        let result = match waitpid(child, None) {
+            Err(e) => {
+                // ignore EINTR as it gets sent when we get a SIGCHLD
+                if e.errno() != Errno::EINTR {
+                    let msg = format!("could not waitpid on {}", child);
+                    Err(e).chain_err(|| msg)?;
+                }
+                WaitStatus::StillAlive
+            }
+            Ok(result) => result,
+
+        };
+        match result {
+            WaitStatus::Exited(_, code) => exit_code = code as i32,
+            WaitStatus::Signaled(_, signal, _) => exit_code = signal as i32 + 128,
+            _ => (),
+        };
+    }
+    Ok(exit_code)
+}
+
+fn do_bundle(
+    bundle: &Bundle,
+    image: &str,
+    config: &loopback::Config,
+    dir: Option<&str>,
+    args: &[&str],
+    path: &str,
+) -> Result<i32> {
+    let tools = prepare_tools(image, config, dir)?;
+    // the tools are mounted by the child, in namespaces of its own, so its
+    // session is registered once it reports that they are in
+    let registry = session::Registry::lock()?;
+    let (mounted_r, mounted_w) = pipe2(O_CLOEXEC).chain_err(|| "failed to create pipe")?;
+
+    // the pid namespace is used by children, so set it up before the fork
+    let origpath = "/proc/self/ns/pid";
+    let ofd = open(origpath, OFlag::empty(), Mode::empty()).chain_err(
+        || {
+            format!("failed to open {}", origpath)
+        },
+    )?;
+    defer!(close(ofd).unwrap());
+    bundle.enter_namespaces(CLONE_NEWPID)?;
+
+    // a new user namespace only gets its id maps from the parent, so the
+    // child waits for them
+    let new_user = bundle.creates_namespace(CLONE_NEWUSER);
+    let (ready_r, ready_w) = pipe().chain_err(|| "failed to create pipe")?;
+    let (mapped_r, mapped_w) = pipe().chain_err(|| "failed to create pipe")?;
+
+    match fork().chain_err(|| "failed to fork")? {
+        ForkResult::Child => {
+            close(ready_r).unwrap();
+            close(mapped_w).unwrap();
+            close(mounted_r).unwrap();
+            bundle.enter_namespaces(
+                CLONE_NEWNS | CLONE_NEWIPC | CLONE_NEWUTS | CLONE_NEWNET | CLONE_NEWCGROUP,
+            )?;
+            bundle.setup_root(path, &*tools.attach)?;
+            report_mounted(mounted_w, path)?;
+            // the user namespace comes last so that the mounts above are
+            // made with the privileges of the host
+            bundle.enter_namespaces(CLONE_NEWUSER)?;
+            if new_user {
+                write(ready_w, &[0]).chain_err(|| "failed to write to pipe")?;
+                let mut buf = [0u8; 1];
+                if read(mapped_r, &mut buf).chain_err(|| "failed to read from pipe")? == 0 {
+                    bail!("failed to set up user namespace");
+                }
+            }
+            close(ready_w).unwrap();
+            close(mapped_r).unwrap();
+            if bundle.has_namespace(CLONE_NEWUSER) {
+                setresgid(0, 0, 0).chain_err(|| "failed to setgid")?;
+                setresuid(0, 0, 0).chain_err(|| "failed to setuid")?;
+            }
+            env::set_var("CRASHCART", path);
+            let all = command(args, path);
+            execvp(&all[0], &all).chain_err(|| "failed to exec")?;
+            Ok(-1)
+        }
+        ForkResult::Parent { child } => {
+            close(ready_w).unwrap();
+            close(mapped_r).unwrap();
+            close(mounted_w).unwrap();
+            defer!({
+                close(ready_r).unwrap();
+                close(mapped_w).unwrap();
+                close(mounted_r).unwrap();
+            });
+            // reset pid namespace
+            setns(ofd, CLONE_NEWPID).chain_err(|| "failed to setns")?;
+            let mut buf = [0u8; 1];
+            if new_user && read(ready_r, &mut buf).chain_err(|| "failed to read from pipe")? == 1 {
+                let (uid_map, gid_map) = bundle.id_maps();
+                bundle::write_id_maps(child, &uid_map, &gid_map)?;
+                write(mapped_w, &[0]).chain_err(|| "failed to write to pipe")?;
+            }
+            let session = register_child(&registry, child, &tools.name, mounted_r)?;
+            drop(registry);
+            let exit_code = wait_child(child);
+            drop(session);
+            exit_code
+        }
+    }
+}
+
+/// Returns the mount points of the mounts on top of the one at path in the
+/// mount namespace of the target, in the order they can be unmounted.
+/// Mounts that are hidden below the one at path are left out.
+fn submounts(target: &Process, path: &str) -> Vec<String> {
+    let f = match File::open(target.proc_path("mountinfo")) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    // (mount id, parent id, mount point)
+    let mounts: Vec<(String, String, String)> = BufReader::new(f)
+        .lines()
+        .map_while(|l| l.ok())
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split(' ').collect();
+            if fields.len() < 5 {
+                return None;
+            }
+            Some((
+                fields[0].to_string(),
+                fields[1].to_string(),
+                fields[4].replace("\\040", " "),
+            ))
+        })
+        .collect();
+    let top = match mounts.iter().rev().find(|m| m.2 == path) {
+        Some(m) => m.0.clone(),
+        None => return Vec::new(),
+    };
+    // children are listed after their parents
+    let mut ids = vec![top];
+    let mut below = Vec::new();
+    for (id, parent, point) in mounts {
+        if ids.contains(&parent) {
+            ids.push(id);
+            below.push(point);
+        }
+    }
+    below.reverse();
+    below
+}
+
+/// Unmounts the tools from any of paths in the target.
+fn do_unmount_ns(target: &Process, devnr: Option<i32>, paths: &[String]) -> Result<()> {
+    // /proc/self is not usable once in the mount namespace of the target,
+    // so look for mounts below the crashcart mount (from --dir) first
+    let below: Vec<Vec<String>> = paths.iter().map(|p| submounts(target, p)).collect();
+    // if we are in a userns, make sure that we have the right fsids
+    let reset_fsids = set_fsids(target)?;
+    defer!(reset_fsids());
+    let exit_mount_ns = enter_mount_ns(target)?;
+    defer!(exit_mount_ns().unwrap());
+
+    for (path, below) in paths.iter().zip(&below) {
+        if is_mounted(path)? {
+            for m in below {
+                umount(&**m).chain_err(|| format!("could not unmount {}", m))?;
+            }
+            umount(&**path).chain_err(|| format!("could not unmount {}", path))?;
+            // a fallback path may have been in use before
+            if let Err(e) = remove_dir(path) {
+                if e.kind() != std::io::ErrorKind::NotFound &&
+                    e.raw_os_error() != Some(libc::ENOTEMPTY)
+                {
+                    let msg = format!("could not delete {}", path);
+                    Err(e).chain_err(|| msg)?;
+                }
+            }
+        }
+        let loop_tmp = loop_dir(path);
+        if let Some(devnr) = devnr {
+            let ccimage = format!("{}/loop{}", loop_tmp, devnr);
+            if let Err(e) = remove_file(&ccimage) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    let msg = format!("could not delete {}", &ccimage);
+                    Err(e).chain_err(|| msg)?;
+                }
+            }
+        }
+        if is_mounted(&loop_tmp)? {
+            umount(&*loop_tmp).chain_err(|| {
+                format!("could not unmount tmpfs from {}", loop_tmp)
+            })?;
+            if let Err(e) = remove_dir(&loop_tmp) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    let msg = format!("could not delete {}", loop_tmp);
+                    Err(e).chain_err(|| msg)?;
+                }
+            }
         }
     }
+    restore_dev();
+    Ok(())
+}
+
+/// Remounts /dev read only again if it was made writable.
+fn restore_dev() {
     let sentinel = "/dev/readonly";
     if metadata(sentinel).is_ok() {
         // TODO: The userns should be entered first so don't mess up the
@@ -646,28 +1527,394 @@ fn do_unmount_ns(pid: u64, devnr: i32) -> Result<()> {
             }
         }
     }
+}
+
+/// Undoes the journaled steps in the target, last step first. Steps that
+/// were never done, or were undone already, are skipped.
+fn undo(target: &Process, steps: &[Step]) -> Result<()> {
+    // if we are in a userns, make sure that we have the right fsids
+    let reset_fsids = set_fsids(target)?;
+    defer!(reset_fsids());
+    let exit_mount_ns = enter_mount_ns(target)?;
+    defer!(exit_mount_ns().unwrap());
+
+    for step in steps.iter().rev() {
+        debug!("undoing {}", step);
+        match *step {
+            Step::Remount => restore_dev(),
+            Step::Mkdir(ref p) => {
+                if let Err(e) = remove_dir(p) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!("could not delete {}: {}", p, e);
+                    }
+                }
+            }
+            Step::Mknod(ref p) => {
+                if let Err(e) = remove_file(p) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        let msg = format!("could not delete {}", p);
+                        Err(e).chain_err(|| msg)?;
+                    }
+                }
+            }
+            Step::Tmpfs(ref p) | Step::Mount(ref p) => {
+                // also takes anything mounted below along
+                if let Err(e) = umount2(&**p, MNT_DETACH) {
+                    if e.errno() != Errno::EINVAL && e.errno() != Errno::ENOENT {
+                        let msg = format!("could not unmount {}", p);
+                        Err(e).chain_err(|| msg)?;
+                    }
+                }
+            }
+        }
+    }
     Ok(())
 }
 
-fn do_unmount(pid: u64, image: &str) -> Result<()> {
-    let link = format!("{}.link", image);
-    match read_link(&link) {
-        Ok(m) => {
-            let devnr = m.to_str().unwrap()["/dev/loop".len()..]
-                .parse::<i32>()
-                .unwrap();
-            if is_backing(devnr, image) {
-                do_unmount_ns(pid, devnr)?;
-            };
+/// Puts the containers back as they were before crashcart sessions that
+/// did not clean up, all of them or only the one with pid.
+fn do_recover(pid: Option<u64>) -> Result<()> {
+    let only = match pid {
+        Some(pid) => Some(Process::open(pid)?.mount_ns()?),
+        None => None,
+    };
+    let registry = session::Registry::lock()?;
+    let mut found = false;
+    for ns in journal::namespaces()? {
+        if only.is_some() && only != Some(ns) {
+            continue;
         }
-        Err(e) => {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                let msg = format!("could not read {}", image);
-                Err(e).chain_err(|| msg)?;
+        found = true;
+        let running = registry.in_namespace(ns)?;
+        if running > 0 {
+            info!("mount namespace {} is in use by {} running session(s)", ns, running);
+            continue;
+        }
+        let (pids, steps) = journal::read(ns)?;
+        // any process that is still in the namespace will do
+        let target = pids.iter().rev().filter_map(|p| Process::open(*p).ok()).find(|p| {
+            p.mount_ns().ok() == Some(ns)
+        });
+        match target {
+            Some(target) => {
+                undo(&target, &steps)?;
+                info!("recovered namespace of pid {}", target.pid());
+            }
+            None => info!("mount namespace {} is gone", ns),
+        }
+        journal::remove(ns)?;
+    }
+    if !found {
+        info!("nothing to recover");
+    }
+    Ok(())
+}
+
+/// Logs what gc does, or would do on a dry run. Returns whether to do it.
+fn gc_step(dry_run: bool, what: &str) -> bool {
+    if dry_run {
+        info!("would {}", what);
+    } else {
+        info!("{}", what);
+    }
+    !dry_run
+}
+
+/// Cleans up what crashcart left behind: the tools at any of paths in
+/// namespaces without a running session, staged mounts, journals of
+/// namespaces that are gone, loop devices of crashcart images that are
+/// not mounted anywhere and the `.link` and `.lock` files of older
+/// versions next to the images.
+fn do_gc(paths: &[String], image: &str, dry_run: bool) -> Result<()> {
+    // no session can start or end while this is held
+    let registry = session::Registry::lock()?;
+    let namespaces = status::namespaces()?;
+
+    // devices that are still mounted once the leftovers are gone
+    let mut in_use = HashSet::new();
+    let mut leftover = Vec::new();
+    for (&ns, &pid) in &namespaces {
+        let idle = registry.in_namespace(ns)? == 0;
+        for m in status::mounts(pid) {
+            let path = paths.iter().find(|p| {
+                m.point == **p || m.point.starts_with(&format!("{}/", p))
+            });
+            if m.point.starts_with(&format!("{}/", CC_STAGING)) {
+                continue;
+            }
+            match path {
+                // the tools may have been mounted more than once
+                Some(p) if idle => {
+                    if m.point == *p && !leftover.iter().any(|l: &(_, _, String, _)| l.0 == ns && l.2 == *p) {
+                        leftover.push((ns, pid, p.clone(), m.device));
+                    }
+                }
+                _ => {
+                    in_use.insert(m.device);
+                }
+            }
+        }
+    }
+
+    for (ns, pid, path, device) in leftover {
+        if !gc_step(dry_run, &format!("unmount {} in namespace of pid {}", path, pid)) {
+            continue;
+        }
+        let devnr = status::loop_name(&device).and_then(|n| n["loop".len()..].parse::<i32>().ok());
+        let res = Process::open(pid).and_then(|target| {
+            do_unmount_ns(&target, devnr, std::slice::from_ref(&path))
+        });
+        match res {
+            Ok(()) => journal::remove(ns)?,
+            Err(e) => warn!("could not unmount {}: {}", path, chain(&e)),
+        }
+    }
+
+    for ns in journal::namespaces()? {
+        if !namespaces.contains_key(&ns) &&
+            gc_step(dry_run, &format!("delete the journal of mount namespace {}", ns))
+        {
+            journal::remove(ns)?;
+        }
+    }
+
+    gc_staging(dry_run)?;
+    let detached = gc_devices(&in_use, image, dry_run)?;
+    gc_files(&detached, image, dry_run)
+}
+
+/// Unmounts images that were left in the staging area.
+fn gc_staging(dry_run: bool) -> Result<()> {
+    let lockfd = match open(CC_STAGING, O_DIRECTORY, Mode::empty()) {
+        Ok(fd) => fd,
+        Err(_) => return Ok(()),
+    };
+    defer!(close(lockfd).unwrap());
+    flock(lockfd, FlockArg::LockExclusive).chain_err(|| {
+        format!("could not get lock on {}", CC_STAGING)
+    })?;
+    defer!(flock(lockfd, FlockArg::Unlock).unwrap());
+
+    let prefix = format!("{}/", CC_STAGING);
+    for m in status::mounts(u64::from(std::process::id())) {
+        if !m.point.starts_with(&prefix) || !gc_step(dry_run, &format!("unmount {}", m.point)) {
+            continue;
+        }
+        umount(&*m.point).chain_err(|| format!("could not unmount {}", m.point))?;
+        let _ = remove_dir(&m.point);
+    }
+    Ok(())
+}
+
+/// Detaches the loop devices of crashcart images that are not in use and
+/// returns the images they were attached to. Only devices that crashcart
+/// set up under its name, or that hold image, are touched, as devices of
+/// older versions can not be told apart from those of other tools.
+fn gc_devices(in_use: &HashSet<String>, image: &str, dry_run: bool) -> Result<Vec<String>> {
+    let image = metadata(image).ok();
+    let mut detached = Vec::new();
+    let entries = read_dir("/sys/block").chain_err(|| "failed to read /sys/block")?;
+    for entry in entries {
+        let entry = entry.chain_err(|| "failed to read /sys/block")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let devnr = match name.strip_prefix("loop").map(|n| n.parse::<i32>()) {
+            Some(Ok(n)) => n,
+            _ => continue,
+        };
+        let mut backing = String::new();
+        let mut device = String::new();
+        let read = File::open(entry.path().join("loop/backing_file"))
+            .and_then(|mut f| f.read_to_string(&mut backing))
+            .and_then(|_| File::open(entry.path().join("dev")))
+            .and_then(|mut f| f.read_to_string(&mut device));
+        if read.is_err() || in_use.contains(device.trim()) {
+            continue;
+        }
+        let backing = backing.trim().to_string();
+        let lp = format!("/dev/{}", name);
+        let status = open(&*lp, O_RDONLY, Mode::empty()).ok().and_then(|fd| {
+            let status = loopback::loop_get_status64(fd).ok();
+            let _ = close(fd);
+            status
+        });
+        let ours = status.as_ref().is_some_and(|s| {
+            s.name == CC_LOOP_NAME ||
+                image.as_ref().is_some_and(|i| i.dev() == s.device && i.ino() == s.inode)
+        });
+        if !ours {
+            continue;
+        }
+        if gc_step(dry_run, &format!("detach {} from {}", lp, backing)) {
+            if let Err(e) = clear_device(devnr) {
+                warn!("{}", chain(&e));
+                continue;
+            }
+        }
+        detached.push(backing);
+    }
+    Ok(detached)
+}
+
+/// Removes the `.link` and `.lock` files that older versions of crashcart
+/// left next to image and the images of the detached loop devices.
+fn gc_files(detached: &[String], image: &str, dry_run: bool) -> Result<()> {
+    let mut images: Vec<&str> = detached.iter().map(AsRef::as_ref).collect();
+    images.push(image);
+    images.dedup();
+    for image in images {
+        let link = format!("{}.link", image);
+        if let Ok(target) = read_link(&link) {
+            // the link is only of use while its device holds the image
+            let mut backing = String::new();
+            let name = target.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let attached = File::open(format!("/sys/block/{}/loop/backing_file", name))
+                .and_then(|mut f| f.read_to_string(&mut backing))
+                .is_ok() && backing.trim() == image;
+            let stale = !attached || detached.iter().any(|d| d == image);
+            if stale && gc_step(dry_run, &format!("delete {}", link)) {
+                remove_file(&link).chain_err(|| format!("could not delete {}", link))?;
+            }
+        }
+        let lock = format!("{}.lock", image);
+        if let Ok(fd) = open(&*lock, O_RDONLY, Mode::empty()) {
+            defer!(close(fd).unwrap());
+            // an older crashcart may still hold it
+            if flock(fd, FlockArg::LockExclusiveNonblock).is_ok() &&
+                gc_step(dry_run, &format!("delete {}", lock))
+            {
+                remove_file(&lock).chain_err(|| format!("could not delete {}", lock))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn do_unmount(
+    target: &Process,
+    image: &str,
+    config: &loopback::Config,
+    paths: &[String],
+    detach: bool,
+) -> Result<()> {
+    let devnr = find_device(image, config.offset)?.map(|d| d.devnr);
+    do_unmount_ns(target, devnr, paths)?;
+    if let (Some(devnr), true) = (devnr, detach) {
+        // also for devices that were set up before they cleared themselves
+        if let Err(e) = clear_device(devnr) {
+            warn!("{}", chain(&e));
+        }
+    }
+    info!("{} is unloaded from namespace of pid {}", image, target.pid());
+    Ok(())
+}
+
+/// Returns how to set up the loop device for the image. It is always read
+/// only and clears itself once it is no longer mounted anywhere.
+fn loop_config(matches: &getopts::Matches) -> Result<loopback::Config> {
+    let mut config = loopback::Config {
+        flags: loopback::LO_FLAGS_READ_ONLY | loopback::LO_FLAGS_AUTOCLEAR,
+        name: CC_LOOP_NAME.to_string(),
+        ..Default::default()
+    };
+    if matches.opt_present("direct-io") {
+        config.flags |= loopback::LO_FLAGS_DIRECT_IO;
+    }
+    if let Some(size) = matches.opt_str("block-size") {
+        config.block_size = size.parse::<u32>().chain_err(
+            || format!("{} is not a valid block size", size),
+        )?;
+    }
+    if let Some(offset) = matches.opt_str("offset") {
+        config.offset = offset.parse::<u64>().chain_err(
+            || format!("{} is not a valid offset", offset),
+        )?;
+    }
+    Ok(config)
+}
+
+fn run_batch(
+    matches: &getopts::Matches,
+    ids: &[String],
+    cmd: &[String],
+    image: &str,
+    selected: bool,
+) -> Result<i32> {
+    for o in &["e", "w", "b", "pod"] {
+        if matches.opt_present(o) {
+            bail!("{} can not be used with several containers", o);
+        }
+    }
+    if cmd.is_empty() && !matches.opt_present("m") && !matches.opt_present("u") {
+        bail!("a command is needed to run in several containers");
+    }
+    let mut targets: Vec<batch::Target> = ids.iter()
+        .map(|id| {
+            batch::Target {
+                label: id.clone(),
+                // a batch never stops to ask, ambiguous ids just fail
+                pid: get_pid(id, false),
+            }
+        })
+        .collect();
+    if selected {
+        let runtimes = matches.opt_strs("runtime");
+        let labels = matches.opt_strs("label");
+        for c in runtime::list(&runtime::resolvers()) {
+            if !runtimes.is_empty() && !runtimes.iter().any(|r| c.is_runtime(r)) {
+                continue;
+            }
+            if !labels.iter().all(|l| c.has_label(l)) {
+                continue;
             }
+            targets.push(batch::Target {
+                label: c.to_string(),
+                pid: Ok(c.pid),
+            });
+        }
+    }
+    if targets.is_empty() {
+        bail!("no containers matched");
+    }
+
+    let mut args = match matches.opt_str("dir") {
+        Some(dir) => vec!["--dir".to_string(), dir],
+        None => vec!["-i".to_string(), image.to_string()],
+    };
+    if matches.opt_present("direct-io") {
+        args.push("--direct-io".to_string());
+    }
+    for o in &["block-size", "offset"] {
+        if let Some(v) = matches.opt_str(o) {
+            args.push(format!("--{}", o));
+            args.push(v);
         }
     }
-    info!("{} is unloaded from namespace of pid {}", image, pid);
+    for o in &["v", "m", "u"] {
+        if matches.opt_present(o) {
+            args.push(format!("-{}", o));
+        }
+    }
+    if matches.opt_present("private") {
+        args.push("--private".to_string());
+    }
+    if let Some(p) = matches.opt_str("mount-path") {
+        args.push("--mount-path".to_string());
+        args.push(p);
+    }
+    if let Some(p) = matches.opt_str("target-pid") {
+        args.push("--target-pid".to_string());
+        args.push(p);
+    }
+    let parallel = match matches.opt_str("j") {
+        Some(j) => j.parse::<usize>().chain_err(|| format!("{} is not a number", j))?,
+        None => 4,
+    };
+    batch::run(targets, &args, cmd, parallel)
+}
+
+fn do_unmount_dir(target: &Process, dir: &str, paths: &[String]) -> Result<()> {
+    do_unmount_ns(target, None, paths)?;
+    info!("{} is unloaded from namespace of pid {}", dir, target.pid());
     Ok(())
 }
 
@@ -694,7 +1941,8 @@ fn main() {
         print_backtrace(e);
         unsafe {
             if CHILD_PID != 0 {
-                kill(CHILD_PID, Signal::SIGTERM).unwrap();
+                // the child may be gone already
+                let _ = kill(CHILD_PID, Signal::SIGTERM);
             }
         }
         ::std::process::exit(1);
@@ -707,20 +1955,93 @@ fn run() -> Result<()> {
 
     let mut opts = Options::new();
     opts.optopt("i", "image", "image to mount <crashcart.img>", "IMAGE");
+    opts.optopt(
+        "",
+        "dir",
+        "bind mount this host directory instead of an image",
+        "PATH",
+    );
     opts.optflag("h", "help", "display this help and exit");
     opts.optflag("m", "mount", "mount only (do not run command)");
     opts.optflag("e", "exec", "use docker exec instead of setns");
     opts.optflag("u", "unmount", "unmount only (do not run command)");
     opts.optflag("V", "version", "output version information and exit");
     opts.optflag("v", "verbose", "enable more verbose logging");
+    opts.optflag("", "json", "print the list as json");
+    opts.optflag("", "dry-run", "only show what gc would clean up");
+    opts.optflag(
+        "",
+        "private",
+        "mount only in a private copy of the mount namespace",
+    );
+    opts.optflag("", "direct-io", "read the image with direct io");
+    opts.optopt(
+        "",
+        "block-size",
+        "logical block size of the loop device",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "offset",
+        "offset of the filesystem in the image <0>",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "mount-path",
+        "mount the tools here instead of /dev/crashcart (or a fallback)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "pod",
+        "use a kubernetes pod on this node instead of ID",
+        "NAMESPACE/POD",
+    );
+    opts.optopt("c", "container", "container to use in the pod", "NAME");
+    opts.optflag("w", "wait", "wait for the container to start");
+    opts.optflag(
+        "b",
+        "bundle",
+        "debug a stopped container from its OCI bundle (ID may be a bundle path)",
+    );
+    opts.optopt(
+        "",
+        "target-pid",
+        "use the process with this pid in the container instead of its init",
+        "PID",
+    );
+
+    opts.optflag("", "all", "run in every running container");
+    opts.optmulti(
+        "",
+        "runtime",
+        "run in every container of this runtime",
+        "NAME",
+    );
+    opts.optmulti(
+        "",
+        "label",
+        "run in every container with this label",
+        "KEY[=VALUE]",
+    );
+    opts.optopt(
+        "j",
+        "parallel",
+        "number of containers to run in at once <4>",
+        "N",
+    );
 
-    let matches = opts.parse(&args[1..]).chain_err(
+    // everything after -- is the command, so that several ids can be given
+    let split = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let matches = opts.parse(&args[1..split]).chain_err(
         || "unable to parse options",
     )?;
 
     if matches.opt_present("h") {
         println!("crashcart - mount crashcart image in container");
-        println!("");
+        println!();
         print_usage(program, &opts);
         return Ok(());
     }
@@ -744,34 +2065,173 @@ fn run() -> Result<()> {
     let image = matches.opt_str("i").unwrap_or_else(
         || "crashcart.img".to_string(),
     );
+    let dir = matches.opt_str("dir");
+    if dir.is_some() && matches.opt_present("i") {
+        bail!("-i and --dir can not be used together");
+    }
+    let config = loop_config(&matches)?;
+    if matches.opt_present("private") &&
+        (matches.opt_present("m") || matches.opt_present("u") || matches.opt_present("e"))
+    {
+        bail!("--private can not be used with -m, -u or -e");
+    }
+
+    if matches.free.first().map(String::as_str) == Some("recover") {
+        let pid = match matches.free.get(1) {
+            Some(id) => Some(get_pid(id, true)?),
+            None => None,
+        };
+        return do_recover(pid);
+    }
+
+    if matches.free.first().map(String::as_str) == Some("gc") {
+        return do_gc(&mount_paths(&matches), &image, matches.opt_present("dry-run"));
+    }
+
+    if ["list", "status"].contains(&matches.free.first().map_or("", String::as_str)) {
+        let loaded = status::loaded(&mount_paths(&matches))?;
+        if matches.opt_present("json") {
+            status::print_json(&loaded);
+        } else {
+            status::print_table(&loaded);
+        }
+        return Ok(());
+    }
+
+    let selected = ["all", "runtime", "label"].iter().any(
+        |o| matches.opt_present(o),
+    );
+    let (ids, cmd) = if split < args.len() {
+        (matches.free.clone(), args[split + 1..].to_vec())
+    } else if selected || matches.opt_present("pod") {
+        (Vec::new(), matches.free.clone())
+    } else {
+        let (ids, cmd) = matches.free.split_at(matches.free.len().min(1));
+        (ids.to_vec(), cmd.to_vec())
+    };
 
-    let id = if !matches.free.is_empty() {
-        matches.free[0].clone()
+    if selected || ids.len() > 1 {
+        ::std::process::exit(run_batch(&matches, &ids, &cmd, &image, selected)?);
+    }
+
+    if matches.opt_present("b") {
+        if ids.is_empty() {
+            print_usage(program, &opts);
+            return Ok(());
+        }
+        if matches.opt_present("m") || matches.opt_present("u") || matches.opt_present("e") {
+            bail!("--bundle can not be used with -m, -u or -e");
+        }
+        let bundle = Bundle::open(&ids[0])?;
+        let a: Vec<&str> = cmd.iter().map(AsRef::as_ref).collect();
+        let path = mount_paths(&matches).remove(0);
+        ::std::process::exit(do_bundle(&bundle, &image, &config, dir.as_deref(), &a, &path)?);
+    }
+
+    let wait = matches.opt_present("w");
+    let (id, pid, cmd) = if let Some(pod) = matches.opt_str("pod") {
+        let container = matches.opt_str("c");
+        let find = |r: &[Box<dyn RuntimeResolver>]| {
+            runtime::find_pod_container(r, &pod, container.as_deref())
+        };
+        let c = if wait {
+            wait_for(&pod, |r| find(r).map(Some), |c| c.pid)?
+        } else {
+            find(&runtime::resolvers())?
+        };
+        (c.id, c.pid, cmd)
+    } else if !ids.is_empty() {
+        let id = ids[0].clone();
+        let pid = if wait {
+            wait_for(&id, |r| lookup_pid(r, &id, true, true), |p| *p)?
+        } else {
+            get_pid(&id, true)?
+        };
+        (id, pid, cmd)
     } else {
         print_usage(program, &opts);
         return Ok(());
     };
+    let pid = match matches.opt_str("target-pid") {
+        Some(p) => {
+            let inner = p.parse::<u64>().chain_err(
+                || format!("{} is not a valid pid", p),
+            )?;
+            process::host_pid(pid, inner)?
+        }
+        None => pid,
+    };
+    let target = Process::open(pid)?;
+
+    let private = if matches.opt_present("private") {
+        Some(prepare_tools(&image, &config, dir.as_deref())?)
+    } else {
+        None
+    };
+
+    // sessions are only counted for tools that are mounted in the
+    // namespace of the target itself
+    let tools = dir.clone().unwrap_or_else(|| image.clone());
+    let key = match private {
+        Some(_) => None,
+        None => Some(session::Key::new(&target, &tools)?),
+    };
 
-    let pid = get_pid(&id)?;
-    if !matches.opt_present("u") {
-        do_mount(pid, &image)?;
+    // once mounted, only the path that was used is of interest
+    let mut paths = mount_paths(&matches);
+    let mut session = None;
+    if let (Some(ref key), false) = (&key, matches.opt_present("u")) {
+        let registry = session::Registry::lock()?;
+        let journal = Journal::open(&target)?;
+        let path = do_mount(&target, &image, &config, dir.as_deref(), &paths, &journal)?;
+        if dir.is_none() {
+            warn_fallback(&image, &paths, &path);
+        }
+        if matches.opt_present("m") {
+            registry.pin(key, &target, &path)?;
+        } else {
+            session = Some(registry.register(key, &path)?);
+        }
+        paths = vec![path];
     }
 
     let exit_code = if !matches.opt_present("u") && !matches.opt_present("m") {
-        let a: Vec<&str> = matches.free.iter().map(AsRef::as_ref).collect();
+        let a: Vec<&str> = cmd.iter().map(AsRef::as_ref).collect();
         let docker_id = if matches.opt_present("e") {
             id
         } else {
             String::new()
         };
-        do_exec(pid, &docker_id, &a[1..])?
+        do_exec(&target, &docker_id, &a, &paths, private.as_ref())?
     } else {
         0
     };
 
 
-    if !matches.opt_present("m") {
-        do_unmount(pid, &image)?;
+    if let (Some(ref key), false) = (&key, matches.opt_present("m")) {
+        let registry = session::Registry::lock()?;
+        drop(session);
+        if matches.opt_present("u") {
+            registry.unpin(key)?;
+        }
+        let running = registry.in_namespace(key.ns())?;
+        if running > 0 {
+            info!(
+                "{} stays loaded for {} running session(s) in namespace of pid {}",
+                tools,
+                running,
+                target.pid()
+            );
+        } else {
+            match dir {
+                Some(ref dir) => do_unmount_dir(&target, dir, &paths)?,
+                None => {
+                    let detach = registry.using_tools(key)? == 0;
+                    do_unmount(&target, &image, &config, &paths, detach)?
+                }
+            }
+            journal::remove(key.ns())?;
+        }
     }
     ::std::process::exit(exit_code);
 }
@@ -0,0 +1,69 @@
+use libc;
+use nix::{Errno, Result};
+use nix::NixPath;
+use std::os::unix::io::RawFd;
+
+const OPEN_TREE_CLONE: libc::c_uint = 0x1;
+const OPEN_TREE_CLOEXEC: libc::c_uint = libc::O_CLOEXEC as libc::c_uint;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+pub const MOUNT_ATTR_RDONLY: u64 = 0x1;
+pub const MOUNT_ATTR_NOSUID: u64 = 0x2;
+pub const MOUNT_ATTR_NODEV: u64 = 0x4;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Returns a detached copy of the mount tree at path (with all of its
+/// submounts), which can be attached in another mount namespace.
+pub fn clone_tree<P: ?Sized + NixPath>(path: &P) -> Result<RawFd> {
+    let res = path.with_nix_path(|p| unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            p.as_ptr(),
+            OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC | libc::AT_RECURSIVE as libc::c_uint,
+        )
+    })?;
+    Errno::result(res).map(|fd| fd as RawFd)
+}
+
+/// Sets attrs on every mount in the tree fd.
+pub fn set_attrs(fd: RawFd, attrs: u64) -> Result<()> {
+    let attr = MountAttr {
+        attr_set: attrs,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            fd,
+            b"\0".as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_RECURSIVE,
+            &attr as *const MountAttr,
+            ::std::mem::size_of::<MountAttr>(),
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Attaches the tree fd at target in the current mount namespace.
+pub fn move_mount<P: ?Sized + NixPath>(fd: RawFd, target: &P) -> Result<()> {
+    let res = target.with_nix_path(|t| unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            fd,
+            b"\0".as_ptr(),
+            libc::AT_FDCWD,
+            t.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })?;
+    Errno::result(res).map(drop)
+}
@@ -0,0 +1,250 @@
+use errors::*;
+use libc;
+use nix::sched::CloneFlags;
+use nix::unistd::close;
+use nix::Errno;
+use std::fs::{metadata, read_dir, File};
+use std::io::{BufRead, BufReader};
+use std::io::prelude::*;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::{Duration, SystemTime};
+
+/// A handle on the target process that makes sure every later step acts
+/// on the same process. Where the kernel supports it, the process is held
+/// as a pidfd, which can also be used to enter its namespaces. Its start
+/// time is recorded as well, so that a recycled pid is noticed on kernels
+/// without pidfds.
+pub struct Process {
+    pid: u64,
+    pidfd: Option<RawFd>,
+    start_time: u64,
+}
+
+impl Process {
+    pub fn open(pid: u64) -> Result<Process> {
+        let res = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+        let pidfd = match Errno::result(res) {
+            Ok(fd) => Some(fd as RawFd),
+            Err(e) => {
+                if e.errno() != Errno::ENOSYS {
+                    return Err(e).chain_err(|| format!("failed to open pidfd for {}", pid));
+                }
+                debug!("pidfds are not supported, relying on start time");
+                None
+            }
+        };
+        let mut p = Process {
+            pid,
+            pidfd,
+            start_time: 0,
+        };
+        p.start_time = start_time(pid)?;
+        // the start time was read from /proc, so make sure it is still the
+        // process the pidfd refers to
+        p.check()?;
+        Ok(p)
+    }
+
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Returns the path of file in the /proc directory of the process. Any
+    /// data read from it should only be trusted after a call to check.
+    pub fn proc_path(&self, file: &str) -> String {
+        format!("/proc/{}/{}", self.pid, file)
+    }
+
+    /// Returns the inode of the mount namespace of the process, which names
+    /// the namespace for as long as it exists.
+    pub fn mount_ns(&self) -> Result<u64> {
+        let path = self.proc_path("ns/mnt");
+        let meta = metadata(&path).chain_err(|| format!("failed to stat {}", path))?;
+        Ok(meta.ino())
+    }
+
+    /// Fails if the process has exited or its pid has been reused.
+    pub fn check(&self) -> Result<()> {
+        if let Some(fd) = self.pidfd {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    fd,
+                    0,
+                    ptr::null::<libc::siginfo_t>(),
+                    0,
+                )
+            };
+            if Errno::result(res).is_err() {
+                bail!(ErrorKind::TargetChanged(self.pid));
+            }
+        }
+        match start_time(self.pid) {
+            Ok(t) if t == self.start_time => Ok(()),
+            _ => bail!(ErrorKind::TargetChanged(self.pid)),
+        }
+    }
+
+    /// Enters the given namespaces of the process in one step through the
+    /// pidfd. Returns false if the kernel can not do this (before 5.8), in
+    /// which case the namespaces have to be entered one by one.
+    pub fn setns(&self, namespaces: CloneFlags) -> Result<bool> {
+        let fd = match self.pidfd {
+            Some(fd) => fd,
+            None => return Ok(false),
+        };
+        let res = unsafe { libc::setns(fd, namespaces.bits()) };
+        match Errno::result(res) {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                if e.errno() == Errno::EINVAL {
+                    debug!("setns on a pidfd is not supported");
+                    return Ok(false);
+                }
+                if e.errno() == Errno::ESRCH {
+                    bail!(ErrorKind::TargetChanged(self.pid));
+                }
+                Err(e).chain_err(|| format!("failed to enter namespaces of {}", self.pid))
+            }
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if let Some(fd) = self.pidfd {
+            let _ = close(fd);
+        }
+    }
+}
+
+/// Returns the fields of /proc/<pid>/stat that follow the command, so the
+/// first entry is the state (field 3 in proc(5)).
+pub fn stat(pid: u64) -> Result<Vec<String>> {
+    let path = format!("/proc/{}/stat", pid);
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("failed to read {}", path))?;
+    // the command is in parens and may contain spaces
+    let rest = match contents.rfind(')') {
+        Some(i) => &contents[i + 1..],
+        None => bail!("invalid data in {}", path),
+    };
+    Ok(rest.split_whitespace().map(|f| f.to_string()).collect())
+}
+
+pub fn parent_pid(pid: u64) -> Result<u64> {
+    let fields = stat(pid)?;
+    match fields.get(1).map(|p| p.parse::<u64>()) {
+        Some(Ok(p)) => Ok(p),
+        _ => bail!("invalid parent of {}", pid),
+    }
+}
+
+/// Returns how many seconds ago the process started.
+pub fn uptime(pid: u64) -> Result<u64> {
+    Ok(age(pid)? as u64)
+}
+
+/// Returns when the process started.
+pub fn started_at(pid: u64) -> Result<SystemTime> {
+    let age = Duration::from_millis((age(pid)? * 1000.0) as u64);
+    Ok(SystemTime::now() - age)
+}
+
+fn age(pid: u64) -> Result<f64> {
+    let mut contents = String::new();
+    File::open("/proc/uptime")
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| "failed to read /proc/uptime")?;
+    let now = match contents.split_whitespace().next().map(|u| u.parse::<f64>()) {
+        Some(Ok(u)) => u,
+        _ => bail!("invalid data in /proc/uptime"),
+    };
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    Ok((now - start_time(pid)? as f64 / ticks).max(0.0))
+}
+
+/// Returns the time the process started in clock ticks after boot.
+pub fn start_time(pid: u64) -> Result<u64> {
+    let fields = stat(pid)?;
+    match fields.get(19).map(|t| t.parse::<u64>()) {
+        Some(Ok(t)) => Ok(t),
+        _ => bail!("invalid start time for {}", pid),
+    }
+}
+
+/// Returns the pids of a process in each nested pid namespace it is in,
+/// starting with the pid in the namespace of the caller and ending with
+/// its pid in its own namespace.
+pub fn ns_pids(pid: u64) -> Result<Vec<u64>> {
+    let path = format!("/proc/{}/status", pid);
+    let f = File::open(&path).chain_err(
+        || format!("failed to open {}", path),
+    )?;
+    for line in BufReader::new(f).lines() {
+        let l = line.chain_err(|| format!("failed to read {}", path))?;
+        if !l.starts_with("NSpid:") {
+            continue;
+        }
+        return l["NSpid:".len()..]
+            .split_whitespace()
+            .map(|p| p.parse::<u64>())
+            .collect::<::std::result::Result<Vec<u64>, _>>()
+            .chain_err(|| format!("invalid NSpid in {}", path));
+    }
+    // kernels before 4.1 do not report NSpid
+    Ok(vec![pid])
+}
+
+fn pid_ns(pid: u64) -> Option<(u64, u64)> {
+    let m = metadata(format!("/proc/{}/ns/pid", pid)).ok()?;
+    Some((m.dev(), m.ino()))
+}
+
+/// Returns true if the process or one of its ancestors is in the pid
+/// namespace ns.
+fn in_pid_ns(pid: u64, ns: (u64, u64)) -> bool {
+    let mut p = pid;
+    while p > 1 {
+        if pid_ns(p) == Some(ns) {
+            return true;
+        }
+        p = match parent_pid(p) {
+            Ok(parent) => parent,
+            Err(_) => return false,
+        };
+    }
+    false
+}
+
+/// Finds the host pid of the process that has pid inner in the pid
+/// namespace of the process with host pid outer. The process may be in
+/// that namespace or in one nested below it.
+pub fn host_pid(outer: u64, inner: u64) -> Result<u64> {
+    let ns = match pid_ns(outer) {
+        Some(ns) => ns,
+        None => bail!("failed to read pid namespace of {}", outer),
+    };
+    let level = ns_pids(outer)?.len() - 1;
+    let entries = read_dir("/proc").chain_err(|| "failed to read /proc")?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let pid = match entry.file_name().to_str().map(|p| p.parse::<u64>()) {
+            Some(Ok(p)) => p,
+            _ => continue,
+        };
+        let pids = match ns_pids(pid) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        if pids.get(level) != Some(&inner) || !in_pid_ns(pid, ns) {
+            continue;
+        }
+        debug!("pid {} in namespace of {} is {}", inner, outer, pid);
+        return Ok(pid);
+    }
+    bail!("no process with pid {} in namespace of {}", inner, outer)
+}
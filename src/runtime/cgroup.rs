@@ -0,0 +1,154 @@
+use errors::*;
+use process::ns_pids;
+use runtime::{subdirs, Candidate, Resolution, RuntimeResolver};
+use std::fs::{read_dir, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// only one v1 hierarchy needs to be searched, since runtimes put a
+// container in a cgroup with the same name in every hierarchy
+const V1_HIERARCHIES: &[&str] = &["pids", "memory", "cpu,cpuacct", "systemd"];
+
+/// Finds containers of any runtime that names its cgroups after container
+/// ids, either directly (`<id>`) or as a systemd scope (`docker-<id>.scope`,
+/// `crio-<id>.scope`, `cri-containerd-<id>.scope`, ...). The container's
+/// pid is the process in the cgroup with the lowest pid in the container's
+/// pid namespace.
+pub struct CgroupResolver;
+
+impl RuntimeResolver for CgroupResolver {
+    fn name(&self) -> &str {
+        "cgroup"
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        // containers are usually created two or three levels down, for
+        // example in system.slice or kubepods.slice/kubepods-besteffort.slice
+        let mut paths = match hierarchy() {
+            Some((root, _)) => vec![root],
+            None => return Vec::new(),
+        };
+        for _ in 0..2 {
+            let more: Vec<PathBuf> = paths.iter().flat_map(|p| subdirs(p)).collect();
+            for p in more {
+                if !paths.contains(&p) {
+                    paths.push(p);
+                }
+            }
+        }
+        paths
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(Resolution::NotMine);
+        }
+        let (root, procs) = match hierarchy() {
+            Some(h) => h,
+            None => return Ok(Resolution::NotMine),
+        };
+        let mut matches = Vec::new();
+        find_cgroups(&root, id, &mut matches);
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for (path, prefix, full_id) in matches {
+            let pid = match init_pid(&path, procs) {
+                Some(p) => p,
+                None => continue,
+            };
+            debug!("found cgroup {:?} with pid {}", path, pid);
+            if candidates.iter().any(|c| c.id == full_id) {
+                continue;
+            }
+            let runtime = if prefix.is_empty() {
+                "cgroup".to_string()
+            } else {
+                format!("cgroup/{}", prefix)
+            };
+            candidates.push(Candidate {
+                runtime,
+                id: full_id,
+                pid,
+                ..Default::default()
+            });
+        }
+        Ok(Resolution::from_candidates(candidates))
+    }
+}
+
+/// Returns the hierarchy to search and the name of its process list.
+pub fn hierarchy() -> Option<(PathBuf, &'static str)> {
+    let root = Path::new(CGROUP_ROOT);
+    if root.join("cgroup.controllers").exists() {
+        return Some((root.to_path_buf(), "cgroup.procs"));
+    }
+    V1_HIERARCHIES
+        .iter()
+        .map(|h| root.join(h))
+        .find(|p| p.join("tasks").exists())
+        .map(|p| (p, "tasks"))
+}
+
+/// Splits a cgroup name into the runtime prefix and the container id.
+pub fn split_name(name: &str) -> Option<(String, String)> {
+    let name = name.trim_end_matches(".scope");
+    let (prefix, id) = match name.rfind('-') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => ("", name),
+    };
+    if prefix.ends_with("conmon") || id.len() < 12 ||
+        !id.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    Some((prefix.to_string(), id.to_string()))
+}
+
+fn find_cgroups(dir: &Path, id: &str, out: &mut Vec<(PathBuf, String, String)>) {
+    let entries = match read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        let path = entry.path();
+        let split = entry.file_name().to_str().and_then(split_name);
+        match split {
+            Some((prefix, full_id)) if full_id.starts_with(id) => {
+                out.push((path, prefix, full_id));
+            }
+            _ => find_cgroups(&path, id, out),
+        }
+    }
+}
+
+fn read_procs(dir: &Path, procs: &str, out: &mut Vec<u64>) {
+    if let Ok(f) = File::open(dir.join(procs)) {
+        out.extend(BufReader::new(f).lines().map_while(|l| l.ok()).filter_map(
+            |l| l.trim().parse::<u64>().ok(),
+        ));
+    }
+    if let Ok(entries) = read_dir(dir) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                read_procs(&entry.path(), procs, out);
+            }
+        }
+    }
+}
+
+/// Returns the process in the cgroup (or its children) with the lowest pid
+/// in its own pid namespace, which is the init of the container.
+pub fn init_pid(dir: &Path, procs: &str) -> Option<u64> {
+    let mut pids = Vec::new();
+    read_procs(dir, procs, &mut pids);
+    pids.into_iter()
+        .filter_map(|p| {
+            let inner = ns_pids(p).ok()?.last().cloned()?;
+            Some((inner, p))
+        })
+        .min()
+        .map(|(_, p)| p)
+}
@@ -0,0 +1,73 @@
+use errors::*;
+use glob::glob;
+use runtime::{pid_file_time, read_pid_file, subdirs, Candidate, Resolution, RuntimeResolver};
+use std::path::PathBuf;
+
+/// Finds containers started by containerd shims. The shim keeps its state
+/// in `<base>/<namespace>/<id>/init.pid`, and every namespace (moby for
+/// docker, k8s.io for the cri plugin, default for ctr) is searched.
+pub struct ContainerdResolver {
+    base: &'static str,
+}
+
+impl ContainerdResolver {
+    pub fn new(base: &'static str) -> ContainerdResolver {
+        ContainerdResolver { base }
+    }
+}
+
+impl RuntimeResolver for ContainerdResolver {
+    fn name(&self) -> &str {
+        "containerd"
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(self.base)];
+        paths.extend(subdirs(&paths[0]));
+        paths
+    }
+
+    fn list(&self) -> Result<Vec<Candidate>> {
+        Ok(self.resolve("")?.into_candidates())
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        let pattern = format!("{}/*/{}*/init.pid", self.base, id);
+        let results = glob(&pattern).chain_err(
+            || format!("invalid glob for id {}", id),
+        )?;
+        let mut candidates = Vec::new();
+        for entry in results {
+            let path = match entry {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("failed to read containerd state: {}", e);
+                    continue;
+                }
+            };
+            let (namespace, full_id) = {
+                let mut parts = path.iter().rev().skip(1).filter_map(|c| c.to_str());
+                match (parts.next(), parts.next()) {
+                    (Some(i), Some(n)) => (n.to_string(), i.to_string()),
+                    _ => continue,
+                }
+            };
+            let pid = match read_pid_file(&path) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    warn!("skipping containerd container {}: {}", full_id, e);
+                    continue;
+                }
+            };
+            debug!("found pid file at {:?}", path);
+            candidates.push(Candidate {
+                runtime: format!("containerd/{}", namespace),
+                id: full_id,
+                pid,
+                since: pid_file_time(&path),
+                ..Default::default()
+            });
+        }
+        Ok(Resolution::from_candidates(candidates))
+    }
+}
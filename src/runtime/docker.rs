@@ -0,0 +1,102 @@
+use errors::*;
+use glob::glob;
+use runtime::{read_json, Candidate, Resolution, RuntimeResolver};
+use std::path::{Path, PathBuf};
+
+pub const DOCKER_ROOT: &str = "/var/lib/docker";
+
+/// Finds docker containers by name using the metadata docker keeps in
+/// `<root>/containers/<id>/config.v2.json`. Ids are left to the
+/// containerd resolvers, but names may look like ids too, so a hex name
+/// is only looked up if it is not the prefix of an id.
+pub struct DockerResolver {
+    root: &'static str,
+}
+
+impl DockerResolver {
+    pub fn new(root: &'static str) -> DockerResolver {
+        DockerResolver { root }
+    }
+
+    fn is_id_prefix(&self, id: &str) -> bool {
+        let pattern = format!("{}/containers/{}*", self.root, id);
+        glob(&pattern).ok().is_some_and(|mut r| r.any(|p| p.is_ok()))
+    }
+}
+
+impl RuntimeResolver for DockerResolver {
+    fn name(&self) -> &str {
+        "docker"
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        vec![Path::new(self.root).join("containers")]
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        let hex = id.chars().all(|c| c.is_ascii_hexdigit());
+        if hex && self.is_id_prefix(id) {
+            return Ok(Resolution::NotMine);
+        }
+        let name = format!("/{}", id.trim_start_matches('/'));
+        let pattern = format!("{}/containers/*/config.v2.json", self.root);
+        let results = glob(&pattern).chain_err(
+            || format!("invalid glob {}", pattern),
+        )?;
+        for path in results.filter_map(|p| p.ok()) {
+            let config = match read_json(&path) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("skipping {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            if config["Name"].as_str() != Some(&*name) {
+                continue;
+            }
+            let full_id = config["ID"].as_str().unwrap_or_default().to_string();
+            let state = &config["State"];
+            let pid = state["Pid"].as_u64().unwrap_or(0);
+            if state["Running"].as_bool() != Some(true) || pid == 0 {
+                // a hex id may still be found by the other resolvers
+                if hex {
+                    debug!("docker container {} ({}) is not running", id, full_id);
+                    return Ok(Resolution::NotMine);
+                }
+                bail!("docker container {} ({}) is not running", id, full_id);
+            }
+            debug!("docker container {} is {}", id, full_id);
+            return Ok(Resolution::Found(Candidate {
+                runtime: self.name().to_string(),
+                id: full_id,
+                pid,
+                name: Some(id.to_string()),
+                image: config["Config"]["Image"].as_str().map(|i| i.to_string()),
+                ..Default::default()
+            }));
+        }
+        Ok(Resolution::NotMine)
+    }
+}
+
+/// Fills in the name, image and labels of a docker container from its
+/// metadata.
+pub fn describe(c: &mut Candidate) {
+    let path = format!("{}/containers/{}/config.v2.json", DOCKER_ROOT, c.id);
+    let config = match read_json(path) {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    if c.name.is_none() {
+        c.name = config["Name"].as_str().map(|n| n.trim_start_matches('/').to_string());
+    }
+    if c.image.is_none() {
+        c.image = config["Config"]["Image"].as_str().map(|i| i.to_string());
+    }
+    let labels = config["Config"]["Labels"].as_object().into_iter().flat_map(|l| l.iter());
+    for (k, v) in labels {
+        if let Some(v) = v.as_str() {
+            c.labels.insert(k.to_string(), v.to_string());
+        }
+    }
+}
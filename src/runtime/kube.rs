@@ -0,0 +1,223 @@
+use errors::*;
+use glob::glob;
+use runtime::{annotation, bundle_spec, find, Candidate, RuntimeResolver, NAME_ANNOTATIONS};
+use runtime::cgroup::split_name;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+const KUBELET_PODS: &str = "/var/lib/kubelet/pods";
+const CGROUP_ROOTS: &[&str] = &[
+    "/sys/fs/cgroup",
+    "/sys/fs/cgroup/pids",
+    "/sys/fs/cgroup/memory",
+    "/sys/fs/cgroup/systemd",
+];
+const POD_NAMESPACE_ANNOTATIONS: &[&str] = &[
+    "io.kubernetes.cri.sandbox-namespace",
+    "io.kubernetes.pod.namespace",
+];
+const POD_NAME_ANNOTATIONS: &[&str] = &[
+    "io.kubernetes.cri.sandbox-name",
+    "io.kubernetes.pod.name",
+];
+const TYPE_ANNOTATIONS: &[&str] = &[
+    "io.kubernetes.cri.container-type",
+    "io.kubernetes.cri-o.ContainerType",
+];
+
+struct PodContainer {
+    id: String,
+    name: String,
+}
+
+/// A pod with a kubepods cgroup on this node.
+struct Pod {
+    uid: String,
+    namespace: String,
+    name: String,
+    containers: Vec<PodContainer>,
+}
+
+/// Finds a container of a pod running on this node. The pods are found in
+/// the kubelet pod directories, the container ids in each pod's kubepods
+/// cgroup and the pod and container names in the runtime's bundles. If
+/// the pod runs more than once (for example while it is being replaced),
+/// the id is ambiguous.
+pub fn find_pod_container(
+    resolvers: &[Box<dyn RuntimeResolver>],
+    pod: &str,
+    container: Option<&str>,
+) -> Result<Candidate> {
+    let (namespace, name) = match pod.find('/') {
+        Some(i) => (&pod[..i], &pod[i + 1..]),
+        None => ("default", pod),
+    };
+    let pods: Vec<Pod> = running_pods()?
+        .into_iter()
+        .filter(|p| p.namespace == namespace && p.name == name)
+        .collect();
+    if pods.is_empty() {
+        bail!("pod {}/{} is not running on this node", namespace, name);
+    }
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for p in &pods {
+        debug!("pod {}/{} has uid {}", namespace, name, p.uid);
+        let c = match select(p, container) {
+            Ok(c) => c,
+            Err(e) => {
+                if pods.len() == 1 {
+                    return Err(e);
+                }
+                debug!("skipping pod {}: {}", p.uid, e);
+                continue;
+            }
+        };
+        info!("container {} of pod {}/{} is {}", c.name, namespace, name, c.id);
+        match find(resolvers.iter().map(|r| &**r), &c.id)? {
+            Some(found) => candidates.push(found),
+            None => debug!("could not find pid of container {}", c.id),
+        }
+    }
+    match candidates.len() {
+        0 => bail!("could not find pid of a container of pod {}/{}", namespace, name),
+        1 => Ok(candidates.remove(0)),
+        _ => {
+            for c in &mut candidates {
+                c.describe();
+            }
+            Err(ErrorKind::AmbiguousId(pod.to_string(), candidates).into())
+        }
+    }
+}
+
+/// Returns the container of the pod named container, or the only one.
+fn select<'a>(pod: &'a Pod, container: Option<&str>) -> Result<&'a PodContainer> {
+    let containers = &pod.containers;
+    match container {
+        Some(n) => {
+            match containers.iter().find(|c| c.name == n) {
+                Some(c) => Ok(c),
+                None => {
+                    bail!(
+                        "pod {}/{} has no running container {}, containers are: {}",
+                        pod.namespace,
+                        pod.name,
+                        n,
+                        names(containers)
+                    )
+                }
+            }
+        }
+        None => {
+            match containers.len() {
+                0 => bail!("pod {}/{} has no running containers", pod.namespace, pod.name),
+                1 => Ok(&containers[0]),
+                _ => {
+                    bail!(
+                        "pod {}/{} has several containers, use -c to pick one of: {}",
+                        pod.namespace,
+                        pod.name,
+                        names(containers)
+                    )
+                }
+            }
+        }
+    }
+}
+
+fn names(containers: &[PodContainer]) -> String {
+    containers
+        .iter()
+        .map(|c| &*c.name)
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Returns the pods in the kubelet pod directories that have a kubepods
+/// cgroup. Directories of pods that are gone can stay behind, so pods
+/// without a cgroup or runtime state are left out.
+fn running_pods() -> Result<Vec<Pod>> {
+    let entries = read_dir(KUBELET_PODS).chain_err(
+        || format!("could not read {}", KUBELET_PODS),
+    )?;
+    let mut pods = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let uid = entry.file_name().to_string_lossy().into_owned();
+        let cgroup = match pod_cgroup(&uid) {
+            Some(c) => c,
+            None => {
+                debug!("skipping pod {}: no kubepods cgroup", uid);
+                continue;
+            }
+        };
+        debug!("pod {} has cgroup {:?}", uid, cgroup);
+        match pod(uid, &cgroup) {
+            Ok(Some(p)) => pods.push(p),
+            Ok(None) => {}
+            Err(e) => warn!("skipping pod in {:?}: {}", cgroup, e),
+        }
+    }
+    Ok(pods)
+}
+
+fn pod_cgroup(uid: &str) -> Option<PathBuf> {
+    // the systemd cgroup driver replaces dashes in the uid with underscores
+    let uids = [uid.to_string(), uid.replace('-', "_")];
+    for root in CGROUP_ROOTS {
+        for u in &uids {
+            let pattern = format!("{}/kubepods*/**/*pod{}*", root, u);
+            let found = glob(&pattern).ok().and_then(|mut r| {
+                r.find(|p| p.as_ref().map(|p| p.is_dir()).unwrap_or(false))
+            });
+            if let Some(Ok(path)) = found {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/// Reads the pod with uid from the runtime state of the containers in its
+/// cgroup. Returns None if the runtime has no state for it.
+fn pod(uid: String, cgroup: &Path) -> Result<Option<Pod>> {
+    let entries = read_dir(cgroup).chain_err(
+        || format!("could not read {:?}", cgroup),
+    )?;
+    let mut pod_name = None;
+    let mut containers = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let id = match entry.file_name().to_str().and_then(split_name) {
+            Some((_, i)) => i,
+            None => continue,
+        };
+        let spec = match bundle_spec(&id) {
+            Some(s) => s,
+            None => {
+                warn!("could not find runtime state for container {}", id);
+                continue;
+            }
+        };
+        let ns = annotation(&spec, POD_NAMESPACE_ANNOTATIONS);
+        let n = annotation(&spec, POD_NAME_ANNOTATIONS);
+        if let (Some(ns), Some(n)) = (ns, n) {
+            pod_name = Some((ns.to_string(), n.to_string()));
+        }
+        if annotation(&spec, TYPE_ANNOTATIONS) == Some("sandbox") {
+            continue;
+        }
+        if let Some(name) = annotation(&spec, NAME_ANNOTATIONS) {
+            containers.push(PodContainer {
+                id,
+                name: name.to_string(),
+            });
+        }
+    }
+    Ok(pod_name.map(|(namespace, name)| {
+        Pod {
+            uid,
+            namespace,
+            name,
+            containers,
+        }
+    }))
+}
@@ -0,0 +1,112 @@
+use errors::*;
+use process::parent_pid;
+use runtime::{Candidate, Resolution, RuntimeResolver};
+use runtime::cgroup::{hierarchy, init_pid};
+use std::fs::{read_dir, File};
+use std::io::prelude::*;
+
+const PREFIX: &str = "lxc:";
+const MONITOR: &str = "[lxc monitor]";
+
+/// Finds LXC and Incus system containers given as `lxc:<name>`. The init
+/// of the container is looked up in the `lxc.payload.<name>` (or older
+/// `lxc/<name>`) cgroup. If the cgroup can not be found, the init is the
+/// child of the `[lxc monitor] <lxcpath> <name>` process that liblxc
+/// leaves running next to each container.
+pub struct LxcResolver;
+
+impl RuntimeResolver for LxcResolver {
+    fn name(&self) -> &str {
+        "lxc"
+    }
+
+    fn list(&self) -> Result<Vec<Candidate>> {
+        let mut candidates = Vec::new();
+        for name in monitored() {
+            match self.resolve(&format!("{}{}", PREFIX, name)) {
+                Ok(r) => candidates.extend(r.into_candidates()),
+                Err(e) => debug!("skipping lxc container {}: {}", name, e),
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        if !id.starts_with(PREFIX) {
+            return Ok(Resolution::NotMine);
+        }
+        let name = &id[PREFIX.len()..];
+        if name.is_empty() || name.contains('/') || name.contains("..") {
+            bail!("invalid lxc container name {:?}", name);
+        }
+        let pid = match cgroup_init(name).or_else(|| monitor_child(name)) {
+            Some(pid) => pid,
+            None => bail!("lxc container {} is not running", name),
+        };
+        Ok(Resolution::Found(Candidate {
+            runtime: self.name().to_string(),
+            id: name.to_string(),
+            pid,
+            ..Default::default()
+        }))
+    }
+}
+
+fn cmdline(pid: &str) -> Option<String> {
+    let mut contents = String::new();
+    File::open(format!("/proc/{}/cmdline", pid))
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .ok()?;
+    Some(contents)
+}
+
+fn pids() -> Vec<String> {
+    match read_dir("/proc") {
+        Ok(entries) => {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|p| p.chars().all(|c| c.is_ascii_digit()))
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Returns the container name if pid is an lxc monitor.
+fn monitor_of(pid: &str) -> Option<String> {
+    let c = cmdline(pid)?;
+    if !c.starts_with(MONITOR) {
+        return None;
+    }
+    c.split(['\0', ' '])
+        .rfind(|a| !a.is_empty())
+        .map(|n| n.to_string())
+}
+
+/// Returns the names of the containers that have a monitor.
+fn monitored() -> Vec<String> {
+    pids().iter().filter_map(|p| monitor_of(p)).collect()
+}
+
+fn monitor_child(name: &str) -> Option<u64> {
+    let pids = pids();
+    let monitor = pids.iter().find(
+        |p| monitor_of(p).as_deref() == Some(name),
+    )?;
+    debug!("found lxc monitor for {} with pid {}", name, monitor);
+    let monitor = monitor.parse::<u64>().ok()?;
+    pids.iter()
+        .filter_map(|p| p.parse::<u64>().ok())
+        .find(|p| parent_pid(*p).ok() == Some(monitor))
+}
+
+fn cgroup_init(name: &str) -> Option<u64> {
+    let (root, procs) = hierarchy()?;
+    [format!("lxc.payload.{}", name), format!("lxc/{}", name)]
+        .iter()
+        .map(|c| root.join(c))
+        .filter(|p| p.is_dir())
+        .filter_map(|p| init_pid(&p, procs))
+        .next()
+}
@@ -0,0 +1,76 @@
+use errors::*;
+use runtime::{Candidate, Resolution, RuntimeResolver};
+use std::fs::{read_dir, File};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::PathBuf;
+
+const PREFIX: &str = "machine:";
+const MACHINES: &str = "/run/systemd/machines";
+
+/// Finds systemd-nspawn (or any other machined registered) containers
+/// given as `machine:<name>` using the `LEADER=` entry that machined
+/// keeps in `/run/systemd/machines/<name>`.
+pub struct MachineResolver;
+
+impl RuntimeResolver for MachineResolver {
+    fn name(&self) -> &str {
+        "machine"
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        vec![PathBuf::from(MACHINES)]
+    }
+
+    fn list(&self) -> Result<Vec<Candidate>> {
+        let mut candidates = Vec::new();
+        let entries = match read_dir(MACHINES) {
+            Ok(e) => e,
+            Err(_) => return Ok(candidates),
+        };
+        for name in entries.filter_map(|e| e.ok()).filter_map(|e| e.file_name().into_string().ok()) {
+            // machined also links each unit to its machine
+            if name.starts_with("unit:") {
+                continue;
+            }
+            match self.resolve(&format!("{}{}", PREFIX, name)) {
+                Ok(r) => candidates.extend(r.into_candidates()),
+                Err(e) => debug!("skipping machine {}: {}", name, e),
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        if !id.starts_with(PREFIX) {
+            return Ok(Resolution::NotMine);
+        }
+        let name = &id[PREFIX.len()..];
+        if name.is_empty() || name.contains('/') || name.contains("..") {
+            bail!("invalid machine name {:?}", name);
+        }
+        let path = format!("{}/{}", MACHINES, name);
+        let f = match File::open(&path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                bail!("machine {} is not running", name)
+            }
+            Err(e) => return Err(e).chain_err(|| format!("failed to open {}", path)),
+        };
+        for line in BufReader::new(f).lines() {
+            let l = line.chain_err(|| format!("failed to read {}", path))?;
+            if !l.starts_with("LEADER=") {
+                continue;
+            }
+            let pid = l["LEADER=".len()..].parse::<u64>().chain_err(|| {
+                format!("invalid leader in {}", path)
+            })?;
+            return Ok(Resolution::Found(Candidate {
+                runtime: self.name().to_string(),
+                id: name.to_string(),
+                pid,
+                ..Default::default()
+            }));
+        }
+        bail!("machine {} has no leader", name)
+    }
+}
@@ -0,0 +1,438 @@
+use errors::*;
+use glob::glob;
+use process;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{metadata, File};
+use std::io::prelude::*;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+mod cgroup;
+mod containerd;
+mod docker;
+mod kube;
+mod lxc;
+mod machine;
+mod storage;
+
+pub use self::cgroup::CgroupResolver;
+pub use self::containerd::ContainerdResolver;
+pub use self::docker::{DockerResolver, DOCKER_ROOT};
+pub use self::kube::find_pod_container;
+pub use self::lxc::LxcResolver;
+pub use self::machine::MachineResolver;
+pub use self::storage::StorageResolver;
+
+const BUNDLE_GLOBS: &[&str] = &[
+    "/run/containerd/io.containerd.runtime.v2.task/*/{}/config.json",
+    "/run/containers/storage/*-containers/{}/userdata/config.json",
+];
+pub const NAME_ANNOTATIONS: &[&str] = &[
+    "io.kubernetes.cri.container-name",
+    "io.kubernetes.container.name",
+];
+const IMAGE_ANNOTATIONS: &[&str] = &[
+    "io.kubernetes.cri.image-name",
+    "io.kubernetes.cri-o.ImageName",
+];
+const CRIO_LABELS: &str = "io.kubernetes.cri-o.Labels";
+const RUNTIME_ALIASES: &[(&str, &str)] = &[("docker", "containerd/moby")];
+
+/// A container that matched an id, along with the runtime that owns it.
+/// The name, image, labels and uptime are only filled in when they are
+/// needed to tell several candidates apart or to select containers.
+#[derive(Clone, Debug, Default)]
+pub struct Candidate {
+    pub runtime: String,
+    pub id: String,
+    pub pid: u64,
+    pub name: Option<String>,
+    pub image: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub uptime: Option<u64>,
+    /// when the runtime wrote the pid, for pids read from a pid file
+    pub since: Option<SystemTime>,
+}
+
+impl Candidate {
+    /// Fills in whatever details can be read from the runtime metadata.
+    pub fn describe(&mut self) {
+        docker::describe(self);
+        if let Some(spec) = bundle_spec(&self.id) {
+            if self.name.is_none() {
+                self.name = annotation(&spec, NAME_ANNOTATIONS).map(|n| n.to_string());
+            }
+            if self.image.is_none() {
+                self.image = annotation(&spec, IMAGE_ANNOTATIONS).map(|i| i.to_string());
+            }
+            // cri-o keeps the pod labels as json in an annotation
+            let pod_labels = annotation(&spec, &[CRIO_LABELS])
+                .and_then(|l| serde_json::from_str::<serde_json::Value>(l).ok());
+            let annotations = spec["annotations"].as_object().into_iter().flat_map(|a| a.iter());
+            let pod_labels = pod_labels.iter().filter_map(|l| l.as_object()).flat_map(|l| l.iter());
+            for (k, v) in annotations.chain(pod_labels) {
+                if let Some(v) = v.as_str() {
+                    self.labels.entry(k.to_string()).or_insert_with(|| v.to_string());
+                }
+            }
+        }
+        self.uptime = process::uptime(self.pid).ok();
+    }
+
+    /// Returns true if the process of the container is still there. Pid
+    /// files can outlive their container and the pid be reused, so a
+    /// process that started after its pid was written is someone else.
+    pub fn is_live(&self) -> bool {
+        let started = match process::started_at(self.pid) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        match self.since {
+            // start times are only as accurate as a clock tick
+            Some(since) => started <= since + Duration::from_secs(1),
+            None => true,
+        }
+    }
+
+    /// Returns true if the container was started by runtime. A runtime also
+    /// matches its namespaces (containerd matches containerd/k8s.io) and
+    /// docker matches the containerd namespace it uses.
+    pub fn is_runtime(&self, runtime: &str) -> bool {
+        let alias = RUNTIME_ALIASES.iter().find(|a| a.0 == runtime).map(|a| a.1);
+        [Some(runtime), alias].iter().filter_map(|r| *r).any(|r| {
+            self.runtime == r || self.runtime.starts_with(&format!("{}/", r))
+        })
+    }
+
+    /// Returns true if the container has the label given as `key=value`,
+    /// or has a label named key with any value.
+    pub fn has_label(&self, label: &str) -> bool {
+        let mut parts = label.splitn(2, '=');
+        let key = parts.next().unwrap_or_default();
+        match (self.labels.get(key), parts.next()) {
+            (Some(v), Some(value)) => v == value,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m{}s", s / 60, s % 60),
+        s if s < 86400 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.runtime, self.id)?;
+        let details: Vec<&str> = self.name.iter().chain(self.image.iter()).map(|s| &**s).collect();
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        write!(f, " pid {}", self.pid)?;
+        if let Some(uptime) = self.uptime {
+            write!(f, " up {}", format_duration(uptime))?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Resolution {
+    NotMine,
+    Found(Candidate),
+    Ambiguous(Vec<Candidate>),
+}
+
+impl Resolution {
+    pub fn from_candidates(mut candidates: Vec<Candidate>) -> Resolution {
+        match candidates.len() {
+            0 => Resolution::NotMine,
+            1 => Resolution::Found(candidates.remove(0)),
+            _ => Resolution::Ambiguous(candidates),
+        }
+    }
+
+    pub fn into_candidates(self) -> Vec<Candidate> {
+        match self {
+            Resolution::NotMine => Vec::new(),
+            Resolution::Found(c) => vec![c],
+            Resolution::Ambiguous(cs) => cs,
+        }
+    }
+}
+
+/// Knows how to turn a container id into a pid for one container runtime.
+pub trait RuntimeResolver {
+    fn name(&self) -> &str;
+    fn resolve(&self, id: &str) -> Result<Resolution>;
+
+    /// Returns the directories the runtime creates state in when a
+    /// container starts, so that new containers can be waited for.
+    fn watch(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Returns every running container of the runtime. Resolvers that can
+    /// only look containers up by id return none.
+    fn list(&self) -> Result<Vec<Candidate>> {
+        Ok(Vec::new())
+    }
+}
+
+pub fn read_pid_file<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let mut f = File::open(path).chain_err(
+        || format!("could not open {:?}", path),
+    )?;
+    let mut out = String::new();
+    f.read_to_string(&mut out).chain_err(
+        || format!("could not read {:?}", path),
+    )?;
+    out.trim().parse::<u64>().chain_err(|| {
+        format!("{} in {:?} is not a valid pid", out.trim(), path)
+    })
+}
+
+/// Returns when the pid file was written.
+pub fn pid_file_time<P: AsRef<Path>>(path: P) -> Option<SystemTime> {
+    metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub fn read_json<P: AsRef<Path>>(path: P) -> Result<serde_json::Value> {
+    let path = path.as_ref();
+    let f = File::open(path).chain_err(
+        || format!("could not open {:?}", path),
+    )?;
+    serde_json::from_reader(f).chain_err(|| format!("could not parse {:?}", path))
+}
+
+/// Returns the OCI spec of a container from the bundle its runtime keeps in
+/// the state directory.
+pub fn bundle_spec(id: &str) -> Option<serde_json::Value> {
+    BUNDLE_GLOBS
+        .iter()
+        .filter_map(|g| glob(&g.replace("{}", id)).ok())
+        .flat_map(|r| r.filter_map(|p| p.ok()))
+        .filter_map(|p| read_json(p).ok())
+        .next()
+}
+
+/// Returns the bundle directory of the container with id (or an id prefix),
+/// or id itself if it is the path of a bundle. Unlike the pid of a
+/// container, the bundle can still be there after the container exited.
+pub fn find_bundle(id: &str) -> Result<PathBuf> {
+    if Path::new(id).join("config.json").is_file() {
+        return Ok(PathBuf::from(id));
+    }
+    let mut dirs: Vec<PathBuf> = BUNDLE_GLOBS
+        .iter()
+        .filter_map(|g| glob(&g.replace("{}", &format!("{}*", id))).ok())
+        .flat_map(|r| r.filter_map(|p| p.ok()))
+        .filter_map(|p| p.parent().map(|d| d.to_path_buf()))
+        .collect();
+    match dirs.len() {
+        0 => bail!("no bundle found for {}", id),
+        1 => Ok(dirs.remove(0)),
+        _ => {
+            let all: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+            bail!("id {} matches several bundles:\n    {}", id, all.join("\n    "))
+        }
+    }
+}
+
+pub fn annotation<'a>(spec: &'a serde_json::Value, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().filter_map(|k| spec["annotations"][*k].as_str()).next()
+}
+
+/// Finds containers by globbing for a pid file. The path component of the
+/// pattern that contains `{}` is the container id.
+pub struct PidFileResolver {
+    name: &'static str,
+    pattern: &'static str,
+}
+
+impl PidFileResolver {
+    pub fn new(name: &'static str, pattern: &'static str) -> PidFileResolver {
+        PidFileResolver { name, pattern }
+    }
+}
+
+impl RuntimeResolver for PidFileResolver {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        let literal: Vec<&str> = self.pattern
+            .split('/')
+            .take_while(|c| !c.contains("{}") && !c.contains('*'))
+            .collect();
+        vec![PathBuf::from(literal.join("/"))]
+    }
+
+    fn list(&self) -> Result<Vec<Candidate>> {
+        Ok(self.resolve("")?.into_candidates())
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        let index = match self.pattern.split('/').position(|c| c.contains("{}")) {
+            Some(i) => i,
+            None => bail!("pattern {} has no id component", self.pattern),
+        };
+        let results = glob(&self.pattern.replace("{}", id))
+            .chain_err(|| format!("invalid glob for id {}", id))?;
+        let mut candidates = Vec::new();
+        for entry in results {
+            let path = match entry {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("failed to read {}: {}", self.name, e);
+                    continue;
+                }
+            };
+            let full_id = match path.to_str().and_then(|p| p.split('/').nth(index)) {
+                Some(i) => i.to_string(),
+                None => continue,
+            };
+            let pid = match read_pid_file(&path) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    warn!("skipping {} container {}: {}", self.name, full_id, e);
+                    continue;
+                }
+            };
+            debug!("found pid file at {:?}", path);
+            candidates.push(Candidate {
+                runtime: self.name.to_string(),
+                id: full_id,
+                pid,
+                since: pid_file_time(&path),
+                ..Default::default()
+            });
+        }
+        Ok(Resolution::from_candidates(candidates))
+    }
+}
+
+/// Returns the resolvers for all supported runtimes in the order they are
+/// tried.
+pub fn resolvers() -> Vec<Box<dyn RuntimeResolver>> {
+    vec![
+        Box::new(LxcResolver),
+        Box::new(MachineResolver),
+        Box::new(ContainerdResolver::new(
+            "/run/containerd/io.containerd.runtime.v2.task",
+        )),
+        Box::new(ContainerdResolver::new(
+            "/run/containerd/io.containerd.runtime.v1.linux",
+        )),
+        Box::new(DockerResolver::new(DOCKER_ROOT)),
+        Box::new(StorageResolver::new()),
+        Box::new(PidFileResolver::new(
+            "docker",
+            "/var/run/docker/libcontainerd/containerd/{}*/init/pid",
+        )),
+        Box::new(PidFileResolver::new("rkt", "/var/lib/rkt/pods/run/{}*/pid")),
+        Box::new(CgroupResolver),
+    ]
+}
+
+/// Returns the resolvers to use in the root of another container. The
+/// cgroup resolver is left out, as the pids in cgroup.procs are those of
+/// the pid namespace of the reader, not of the container.
+pub fn nested(resolvers: &[Box<dyn RuntimeResolver>]) -> Vec<&dyn RuntimeResolver> {
+    resolvers.iter().map(|r| &**r).filter(|r| r.name() != "cgroup").collect()
+}
+
+/// Returns the subdirectories of dir.
+pub fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    match read_dir(dir) {
+        Ok(entries) => {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                .map(|e| e.path())
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Returns the directories to watch for new containers of any runtime.
+pub fn watch_paths(resolvers: &[Box<dyn RuntimeResolver>]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for p in resolvers.iter().flat_map(|r| r.watch()) {
+        if !paths.contains(&p) {
+            paths.push(p);
+        }
+    }
+    paths
+}
+
+/// Returns the running containers of all runtimes, described. As with find,
+/// containers found by several resolvers are only listed once.
+pub fn list(resolvers: &[Box<dyn RuntimeResolver>]) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for r in resolvers {
+        let found = match r.list() {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("{} failed to list containers: {}", r.name(), e);
+                continue;
+            }
+        };
+        for c in found {
+            if !c.is_live() {
+                debug!("skipping {} container {}: pid {} is gone", c.runtime, c.id, c.pid);
+                continue;
+            }
+            if !candidates.iter().any(|o| o.pid == c.pid) {
+                candidates.push(c);
+            }
+        }
+    }
+    for c in &mut candidates {
+        c.describe();
+    }
+    candidates
+}
+
+/// Asks each resolver about id in order. Matches from different resolvers
+/// that point at the same process are treated as one container.
+pub fn find<'a, I>(resolvers: I, id: &str) -> Result<Option<Candidate>>
+where
+    I: IntoIterator<Item = &'a dyn RuntimeResolver>,
+{
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for r in resolvers {
+        let found = r.resolve(id)
+            .chain_err(|| format!("{} failed to resolve {}", r.name(), id))?
+            .into_candidates();
+        for c in found {
+            if !c.is_live() {
+                debug!("skipping {} container {}: pid {} is gone", c.runtime, c.id, c.pid);
+                continue;
+            }
+            if !candidates.iter().any(|o| o.pid == c.pid) {
+                candidates.push(c);
+            }
+        }
+    }
+    match candidates.len() {
+        0 => Ok(None),
+        1 => Ok(Some(candidates.remove(0))),
+        _ => {
+            for c in &mut candidates {
+                c.describe();
+            }
+            Err(ErrorKind::AmbiguousId(id.to_string(), candidates).into())
+        }
+    }
+}
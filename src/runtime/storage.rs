@@ -0,0 +1,204 @@
+use errors::*;
+use glob::glob;
+use runtime::{pid_file_time, read_json, read_pid_file, subdirs, Candidate, Resolution, RuntimeResolver};
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Finds CRI-O and Podman containers. Both keep their per container run
+/// state in containers/storage, where conmon writes the container pid to
+/// `<runroot>/<driver>-containers/<id>/userdata/pidfile`. Container names
+/// are read from `containers.json` in the graph root.
+pub struct StorageResolver {
+    run_roots: Vec<PathBuf>,
+    graph_roots: Vec<PathBuf>,
+    libpod_dirs: Vec<PathBuf>,
+}
+
+impl StorageResolver {
+    pub fn new() -> StorageResolver {
+        let mut r = StorageResolver {
+            run_roots: vec![PathBuf::from("/run/containers/storage")],
+            graph_roots: vec![PathBuf::from("/var/lib/containers/storage")],
+            libpod_dirs: vec![PathBuf::from("/run/libpod")],
+        };
+        // rootless podman keeps its state in the runtime dir and home
+        // directory of each user
+        for (uid, home) in users() {
+            let run = PathBuf::from(format!("/run/user/{}", uid));
+            if !run.is_dir() {
+                continue;
+            }
+            r.add_rootless(&run, &home.join(".local/share"));
+        }
+        let run = xdg_dir("XDG_RUNTIME_DIR", None);
+        let data = xdg_dir("XDG_DATA_HOME", Some(".local/share"));
+        if let (Some(run), Some(data)) = (run, data) {
+            r.add_rootless(&run, &data);
+        }
+        r
+    }
+
+    fn add_rootless(&mut self, run: &Path, data: &Path) {
+        let run_root = run.join("containers");
+        if !self.run_roots.contains(&run_root) {
+            self.run_roots.push(run_root);
+            self.graph_roots.push(data.join("containers/storage"));
+            self.libpod_dirs.push(run.join("libpod/tmp"));
+        }
+    }
+
+    /// Returns the id of the container with the given name, if any.
+    fn lookup_name(&self, name: &str) -> Option<String> {
+        for root in &self.graph_roots {
+            let pattern = format!("{}/*-containers/containers.json", root.display());
+            let paths = match glob(&pattern) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            for path in paths.filter_map(|p| p.ok()) {
+                let containers = match read_json(&path) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("skipping {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                for c in containers.as_array().into_iter().flat_map(|a| a.iter()) {
+                    let names = c["names"].as_array().into_iter().flat_map(|a| a.iter());
+                    if names.filter_map(|n| n.as_str()).any(|n| n == name) {
+                        if let Some(id) = c["id"].as_str() {
+                            debug!("container name {} is {}", name, id);
+                            return Some(id.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn has_exited(&self, id: &str) -> bool {
+        self.libpod_dirs.iter().any(
+            |d| d.join("exits").join(id).exists(),
+        )
+    }
+}
+
+impl RuntimeResolver for StorageResolver {
+    fn name(&self) -> &str {
+        "containers-storage"
+    }
+
+    fn watch(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for root in &self.run_roots {
+            paths.push(root.clone());
+            paths.extend(subdirs(root).into_iter().filter(|d| {
+                d.to_string_lossy().ends_with("-containers")
+            }));
+        }
+        paths
+    }
+
+    fn list(&self) -> Result<Vec<Candidate>> {
+        Ok(self.resolve("")?.into_candidates())
+    }
+
+    fn resolve(&self, id: &str) -> Result<Resolution> {
+        let (prefix, named) = match self.lookup_name(id) {
+            Some(full_id) => (full_id, true),
+            None => (format!("{}*", id), false),
+        };
+        let mut candidates = Vec::new();
+        for root in &self.run_roots {
+            let pattern = format!("{}/*-containers/{}/userdata", root.display(), prefix);
+            let results = glob(&pattern).chain_err(
+                || format!("invalid glob for id {}", id),
+            )?;
+            for userdata in results.filter_map(|p| p.ok()) {
+                let full_id = match userdata.parent().and_then(|p| p.file_name()) {
+                    Some(i) => i.to_string_lossy().into_owned(),
+                    None => continue,
+                };
+                if self.has_exited(&full_id) {
+                    debug!("skipping container {}: it has exited", full_id);
+                    continue;
+                }
+                let pidfile = userdata.join("pidfile");
+                let pid = match read_pid_file(&pidfile) {
+                    Ok(pid) => pid,
+                    Err(e) => {
+                        debug!("skipping container {}: {}", full_id, e);
+                        continue;
+                    }
+                };
+                let c = Candidate {
+                    runtime: runtime_of(&userdata).to_string(),
+                    id: full_id,
+                    pid,
+                    name: if named { Some(id.to_string()) } else { None },
+                    since: pid_file_time(&pidfile),
+                    ..Default::default()
+                };
+                // the pidfile stays behind when conmon is killed
+                if !c.is_live() {
+                    debug!("skipping container {}: pid {} is gone", c.id, pid);
+                    continue;
+                }
+                candidates.push(c);
+            }
+        }
+        if named && candidates.is_empty() {
+            if self.has_exited(&prefix) {
+                bail!("container {} ({}) has exited", id, prefix);
+            }
+            bail!("container {} ({}) is not running", id, prefix);
+        }
+        Ok(Resolution::from_candidates(candidates))
+    }
+}
+
+/// CRI-O marks the specs it generates with its own annotations, anything
+/// else in containers/storage was started by podman.
+fn runtime_of(userdata: &Path) -> &'static str {
+    let spec = match read_json(userdata.join("config.json")) {
+        Ok(s) => s,
+        Err(_) => return "podman",
+    };
+    let crio = spec["annotations"].as_object().is_some_and(|a| {
+        a.keys().any(|k| k.starts_with("io.kubernetes.cri-o."))
+    });
+    if crio { "cri-o" } else { "podman" }
+}
+
+fn xdg_dir(var: &str, home_default: Option<&str>) -> Option<PathBuf> {
+    if let Some(dir) = env::var_os(var) {
+        return Some(PathBuf::from(dir));
+    }
+    match (env::var_os("HOME"), home_default) {
+        (Some(home), Some(d)) => Some(Path::new(&home).join(d)),
+        _ => None,
+    }
+}
+
+/// Returns the uid and home directory of every user in /etc/passwd.
+fn users() -> Vec<(u32, PathBuf)> {
+    let f = match File::open("/etc/passwd") {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    BufReader::new(f)
+        .lines()
+        .map_while(|l| l.ok())
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split(':').collect();
+            if fields.len() < 6 {
+                return None;
+            }
+            let uid = fields[2].parse::<u32>().ok()?;
+            Some((uid, PathBuf::from(fields[5])))
+        })
+        .collect()
+}
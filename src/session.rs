@@ -0,0 +1,224 @@
+use errors::*;
+use nix::fcntl::{flock, open, FlockArg, O_CLOEXEC, O_CREAT, O_RDWR, O_TRUNC};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, getpid, write};
+use nix::Errno;
+use process::Process;
+use std::fs::{create_dir_all, metadata, read_dir, remove_file};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+
+const SESSION_DIR: &str = "/run/crashcart/sessions";
+// sessions started with -m, which stay until -u
+const PIN: &str = "pin";
+
+/// What a session uses: the mount namespace of the target and the tools
+/// (image or directory) mounted in it, both named by device and inode.
+pub struct Key {
+    ns: u64,
+    tools: String,
+}
+
+impl Key {
+    pub fn new(target: &Process, tools: &str) -> Result<Key> {
+        let ns = target.mount_ns()?;
+        let meta = metadata(tools).chain_err(
+            || format!("failed to stat {}", tools),
+        )?;
+        Ok(Key {
+            ns,
+            tools: format!("{}-{}", meta.dev(), meta.ino()),
+        })
+    }
+
+    pub fn ns(&self) -> u64 {
+        self.ns
+    }
+
+    fn prefix(&self) -> String {
+        format!("{}.{}.", self.ns, self.tools)
+    }
+}
+
+/// A running session. It is registered until it is dropped, or until the
+/// process is gone, as the file of the session is locked while it runs.
+pub struct Session {
+    path: String,
+    fd: RawFd,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+        let _ = close(self.fd);
+    }
+}
+
+/// The registry of the sessions on this host, with one file per session
+/// named `NS.TOOLS.PID`. The tools are only unmounted from a namespace
+/// when the last session in it exits, and the loop device is only
+/// detached when no namespace uses the image any more. The registry is
+/// locked for as long as this is alive.
+pub struct Registry {
+    fd: RawFd,
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+impl Registry {
+    pub fn lock() -> Result<Registry> {
+        create_dir_all(SESSION_DIR).chain_err(|| {
+            format!("failed to create {}", SESSION_DIR)
+        })?;
+        let lockp = format!("{}.lock", SESSION_DIR);
+        let fd = open(
+            &*lockp,
+            O_RDWR | O_CREAT | O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        ).chain_err(|| format!("failed to open {}", lockp))?;
+        let registry = Registry { fd };
+        flock(fd, FlockArg::LockExclusive).chain_err(|| {
+            format!("could not get lock on {}", lockp)
+        })?;
+        Ok(registry)
+    }
+
+    /// Registers a session of this process with the tools mounted at path.
+    pub fn register(&self, key: &Key, path: &str) -> Result<Session> {
+        let p = format!("{}/{}{}", SESSION_DIR, key.prefix(), getpid());
+        let fd = open(
+            &*p,
+            O_RDWR | O_CREAT | O_TRUNC | O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        ).chain_err(|| format!("failed to open {}", p))?;
+        let session = Session { path: p, fd };
+        flock(fd, FlockArg::LockExclusive).chain_err(|| {
+            format!("could not get lock on {}", session.path)
+        })?;
+        write(fd, path.as_bytes()).chain_err(|| {
+            format!("failed to write {}", session.path)
+        })?;
+        Ok(session)
+    }
+
+    /// Keeps the tools mounted in the namespace of the target until they
+    /// are unpinned, for mounts from -m.
+    pub fn pin(&self, key: &Key, target: &Process, path: &str) -> Result<()> {
+        let p = format!("{}/{}{}{}", SESSION_DIR, key.prefix(), PIN, target.pid());
+        let fd = open(
+            &*p,
+            O_RDWR | O_CREAT | O_TRUNC | O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        ).chain_err(|| format!("failed to open {}", p))?;
+        defer!(close(fd).unwrap());
+        write(fd, path.as_bytes()).chain_err(
+            || format!("failed to write {}", p),
+        )?;
+        Ok(())
+    }
+
+    /// Removes the pins of the namespace.
+    pub fn unpin(&self, key: &Key) -> Result<()> {
+        let prefix = format!("{}.", key.ns);
+        for name in self.names()? {
+            if name.starts_with(&prefix) && is_pin(&name) {
+                let p = format!("{}/{}", SESSION_DIR, name);
+                remove_file(&p).chain_err(|| format!("could not delete {}", p))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of running sessions in the namespace, with any
+    /// tools, as they all share the mount point.
+    pub fn in_namespace(&self, ns: u64) -> Result<usize> {
+        Ok(self.sessions(ns)?.len())
+    }
+
+    /// Returns the running sessions in the namespace, as the pids of their
+    /// crashcart, or `-m` for tools that were mounted with -m.
+    pub fn sessions(&self, ns: u64) -> Result<Vec<String>> {
+        let prefix = format!("{}.", ns);
+        let names = self.running(|name| name.starts_with(&prefix))?;
+        Ok(
+            names
+                .iter()
+                .map(|name| if is_pin(name) {
+                    "-m".to_string()
+                } else {
+                    name.rsplit('.').next().unwrap_or_default().to_string()
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the number of running sessions that use the tools, in any
+    /// namespace.
+    pub fn using_tools(&self, key: &Key) -> Result<usize> {
+        let tools = format!(".{}.", key.tools);
+        Ok(self.running(|name| name.contains(&tools))?.len())
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        let entries = read_dir(SESSION_DIR).chain_err(
+            || format!("failed to read {}", SESSION_DIR),
+        )?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.chain_err(|| format!("failed to read {}", SESSION_DIR))?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    /// Returns the names of the running sessions that match, and removes
+    /// the ones that were left behind by a crashcart that is gone.
+    fn running<F: Fn(&str) -> bool>(&self, matches: F) -> Result<Vec<String>> {
+        let mut running = Vec::new();
+        for name in self.names()? {
+            if !matches(&name) {
+                continue;
+            }
+            let p = format!("{}/{}", SESSION_DIR, name);
+            if is_running(&name, &p)? {
+                running.push(name);
+            } else {
+                debug!("removing stale session {}", name);
+                let _ = remove_file(&p);
+            }
+        }
+        Ok(running)
+    }
+}
+
+fn is_pin(name: &str) -> bool {
+    name.rsplit('.').next().is_some_and(|s| s.starts_with(PIN))
+}
+
+/// A session runs while its file is locked. A pin stays until the
+/// target of the mount has left the namespace.
+fn is_running(name: &str, path: &str) -> Result<bool> {
+    if is_pin(name) {
+        let ns = name.split('.').next().unwrap_or("");
+        let pid = &name.rsplit('.').next().unwrap_or("")[PIN.len()..];
+        return Ok(
+            metadata(format!("/proc/{}/ns/mnt", pid))
+                .map(|m| m.ino().to_string() == ns)
+                .unwrap_or(false),
+        );
+    }
+    let fd = match open(path, O_RDWR | O_CLOEXEC, Mode::empty()) {
+        Ok(fd) => fd,
+        Err(_) => return Ok(false),
+    };
+    defer!(close(fd).unwrap());
+    match flock(fd, FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(false),
+        Err(e) if e.errno() == Errno::EAGAIN => Ok(true),
+        Err(e) => Err(e).chain_err(|| format!("could not check lock on {}", path)),
+    }
+}
@@ -0,0 +1,195 @@
+use errors::*;
+use runtime::{self, Candidate};
+use serde_json::Value;
+use session::Registry;
+use std::collections::BTreeMap;
+use std::fs::{metadata, read_dir, read_link, File};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+
+/// Tools that are loaded in a mount namespace.
+pub struct Loaded {
+    pub ns: u64,
+    pub pid: u64,
+    pub container: Option<Candidate>,
+    pub path: String,
+    /// the backing file of the loop device, or the directory for --dir
+    pub tools: String,
+    pub device: Option<String>,
+    pub sessions: Vec<String>,
+}
+
+/// A mount in the mountinfo of a process.
+pub struct Mount {
+    /// major:minor of the device
+    pub device: String,
+    pub root: String,
+    pub point: String,
+}
+
+fn mount_ns(pid: u64) -> Option<u64> {
+    metadata(format!("/proc/{}/ns/mnt", pid)).ok().map(|m| m.ino())
+}
+
+/// Returns every mount namespace on the host, with the lowest pid in it.
+pub fn namespaces() -> Result<BTreeMap<u64, u64>> {
+    let mut namespaces = BTreeMap::new();
+    for entry in read_dir("/proc").chain_err(|| "failed to read /proc")? {
+        let entry = entry.chain_err(|| "failed to read /proc")?;
+        let pid = match entry.file_name().to_string_lossy().parse::<u64>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        // the process may be gone already
+        if let Some(ns) = mount_ns(pid) {
+            let lowest = namespaces.entry(ns).or_insert(pid);
+            if pid < *lowest {
+                *lowest = pid;
+            }
+        }
+    }
+    Ok(namespaces)
+}
+
+/// Returns the mounts in the mount namespace of pid, in the order they
+/// were mounted. If pid is gone, there are none.
+pub fn mounts(pid: u64) -> Vec<Mount> {
+    let f = match File::open(format!("/proc/{}/mountinfo", pid)) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    BufReader::new(f)
+        .lines()
+        .map_while(|l| l.ok())
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split(' ').collect();
+            if fields.len() < 5 {
+                return None;
+            }
+            Some(Mount {
+                device: fields[2].to_string(),
+                root: fields[3].replace("\\040", " "),
+                point: fields[4].replace("\\040", " "),
+            })
+        })
+        .collect()
+}
+
+/// Returns the name of the loop device with major:minor device, if it is
+/// one.
+pub fn loop_name(device: &str) -> Option<String> {
+    let link = read_link(format!("/sys/dev/block/{}", device)).ok()?;
+    let name = link.file_name()?.to_string_lossy().into_owned();
+    if name.starts_with("loop") {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn backing_file(device: &str) -> Option<String> {
+    let mut backing = String::new();
+    File::open(format!("/sys/dev/block/{}/loop/backing_file", device))
+        .and_then(|mut f| f.read_to_string(&mut backing))
+        .ok()?;
+    Some(backing.trim().to_string())
+}
+
+/// Returns where the tools are loaded at any of paths, in every mount
+/// namespace on the host.
+pub fn loaded(paths: &[String]) -> Result<Vec<Loaded>> {
+    let mut containers = BTreeMap::new();
+    for c in runtime::list(&runtime::resolvers()) {
+        if let Some(ns) = mount_ns(c.pid) {
+            containers.entry(ns).or_insert(c);
+        }
+    }
+    let registry = Registry::lock()?;
+    let mut loaded = Vec::new();
+    for (ns, pid) in namespaces()? {
+        let mounts = mounts(pid);
+        for path in paths {
+            // only the top-most mount at path is visible
+            let m = match mounts.iter().rev().find(|m| m.point == *path) {
+                Some(m) => m,
+                None => continue,
+            };
+            let (tools, device) = match loop_name(&m.device) {
+                Some(name) => {
+                    let tools = backing_file(&m.device).unwrap_or_else(|| "?".to_string());
+                    (tools, Some(format!("/dev/{}", name)))
+                }
+                None => (m.root.clone(), None),
+            };
+            loaded.push(Loaded {
+                ns,
+                pid,
+                container: containers.get(&ns).cloned(),
+                path: path.clone(),
+                tools,
+                device,
+                sessions: registry.sessions(ns)?,
+            });
+        }
+    }
+    Ok(loaded)
+}
+
+pub fn print_table(loaded: &[Loaded]) {
+    let mut rows = vec![
+        ["CONTAINER", "RUNTIME", "PID", "TOOLS", "DEVICE", "PATH", "SESSIONS"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>(),
+    ];
+    for l in loaded {
+        let (id, runtime) = match l.container {
+            Some(ref c) => (c.id.chars().take(12).collect(), c.runtime.clone()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        let sessions = if l.sessions.is_empty() {
+            "-".to_string()
+        } else {
+            l.sessions.join(",")
+        };
+        rows.push(vec![
+            id,
+            runtime,
+            l.pid.to_string(),
+            l.tools.clone(),
+            l.device.clone().unwrap_or_else(|| "-".to_string()),
+            l.path.clone(),
+            sessions,
+        ]);
+    }
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap_or(0))
+        .collect();
+    for row in rows {
+        let cells: Vec<String> = row.iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<1$}", cell, width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+pub fn print_json(loaded: &[Loaded]) {
+    let list: Vec<Value> = loaded
+        .iter()
+        .map(|l| {
+            json!({
+                "container": l.container.as_ref().map(|c| &c.id),
+                "runtime": l.container.as_ref().map(|c| &c.runtime),
+                "name": l.container.as_ref().and_then(|c| c.name.as_ref()),
+                "pid": l.pid,
+                "mount_namespace": l.ns,
+                "tools": l.tools,
+                "device": l.device,
+                "path": l.path,
+                "sessions": l.sessions,
+            })
+        })
+        .collect();
+    println!("{}", Value::Array(list));
+}
@@ -0,0 +1,103 @@
+use errors::*;
+use libc;
+use nix::unistd::close;
+use nix::Errno;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+const EVENTS: u32 = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE;
+
+/// Watches runtime state directories with inotify so that a container is
+/// noticed as soon as its runtime writes its state. Directories created
+/// in a watched directory are watched as well, since runtimes usually
+/// write the pid file into a new directory named after the container.
+pub struct Watcher {
+    fd: RawFd,
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Watcher {
+    pub fn new(paths: &[PathBuf]) -> Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        let fd = Errno::result(fd).chain_err(|| "failed to init inotify")?;
+        let mut w = Watcher {
+            fd,
+            dirs: HashMap::new(),
+        };
+        for p in paths {
+            w.add(p);
+        }
+        Ok(w)
+    }
+
+    fn add(&mut self, path: &Path) {
+        let cpath = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd, cpath.as_ptr(), EVENTS) };
+        if wd < 0 {
+            debug!("could not watch {:?}", path);
+            return;
+        }
+        debug!("watching {:?}", path);
+        self.dirs.insert(wd, path.to_path_buf());
+    }
+
+    /// Waits until something is created in a watched directory or timeout
+    /// milliseconds have passed.
+    pub fn wait(&mut self, timeout: i32) -> Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut pfd, 1, timeout) };
+        if let Err(e) = Errno::result(res) {
+            if e.errno() != Errno::EINTR {
+                return Err(e).chain_err(|| "failed to poll inotify");
+            }
+        }
+        let mut buf = [0u8; 4096];
+        let header = mem::size_of::<libc::inotify_event>();
+        loop {
+            let n = unsafe {
+                libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            };
+            if n <= 0 {
+                break;
+            }
+            let n = n as usize;
+            let mut new_dirs = Vec::new();
+            let mut off = 0;
+            while off + header <= n {
+                let ev = unsafe {
+                    ptr::read_unaligned(buf.as_ptr().add(off) as *const libc::inotify_event)
+                };
+                let name = &buf[off + header..off + header + ev.len as usize];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                if ev.mask & libc::IN_ISDIR != 0 {
+                    if let Some(dir) = self.dirs.get(&ev.wd) {
+                        new_dirs.push(dir.join(OsStr::from_bytes(name)));
+                    }
+                }
+                off += header + ev.len as usize;
+            }
+            for d in new_dirs {
+                self.add(&d);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
@@ -2,6 +2,9 @@
 set -euo pipefail
 
 PACKAGES=${PACKAGES:-}
+# ext3, squashfs or erofs
+FSTYPE=${FSTYPE:-ext3}
+SQUASHFS_COMP=${SQUASHFS_COMP:-xz}
 
 cd /dev/crashcart/
 nix-channel --list | grep nixos-17.09 || nix-channel --add https://nixos.org/channels/nixos-17.09
@@ -16,10 +19,13 @@ sed -i 's;1cks4gsz4148jw6wpqia4w5jx7cfxr29g2kmpvp0ssmvwczh8dr4;27a178398314b81c2
 rm -f profile
 nix-env -p profile -i ${PACKAGES}
 rm -f crashcart.img
-truncate -s 1G crashcart.img
-mkfs.ext3 crashcart.img
+rm -rf out
 mkdir -p out
-mount -t ext2 -o loop crashcart.img out
+if [ "${FSTYPE}" = "ext3" ]; then
+    truncate -s 1G crashcart.img
+    mkfs.ext3 crashcart.img
+    mount -t ext2 -o loop crashcart.img out
+fi
 ln -s "$(readlink -f profile)" out/profile
 ln -s profile/bin out/bin
 ln -s profile/sbin out/sbin
@@ -28,9 +34,25 @@ mkdir -p out/store
 for deps in $(nix-store -qR profile); do
     cp -a  "${deps#/dev/crashcart/*}" out/store/
 done
-umount out
-# We expect this to return 1
-set +e
-e2fsck -f crashcart.img
-set -e
-resize2fs -M crashcart.img
+case "${FSTYPE}" in
+    ext3)
+        umount out
+        # We expect this to return 1
+        set +e
+        e2fsck -f crashcart.img
+        set -e
+        resize2fs -M crashcart.img
+        ;;
+    squashfs)
+        mksquashfs out crashcart.img -comp "${SQUASHFS_COMP}" -all-root -noappend
+        rm -rf out
+        ;;
+    erofs)
+        mkfs.erofs -zlz4hc crashcart.img out
+        rm -rf out
+        ;;
+    *)
+        echo "unknown FSTYPE ${FSTYPE}" >&2
+        exit 1
+        ;;
+esac